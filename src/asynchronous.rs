@@ -0,0 +1,381 @@
+use std::future::Future;
+
+use crate::{
+    pool::{commitment_message, write_commitment},
+    prelude::*,
+    voucher::receipt_id_bounds,
+    BorrowFail, ConcurrentReceiptPool, PartialVoucher, ReceiptPool, Voucher, VoucherConfig,
+    VoucherError,
+};
+
+/// A `Signer` which signs without blocking, e.g. by a request to a remote
+/// signing service. Every `Signer` is also an `AsyncSigner`.
+pub trait AsyncSigner: Sync {
+    /// Signs the message hash, as `Signer::sign_hash` does.
+    fn sign_hash_async(
+        &self,
+        message: &Bytes32,
+    ) -> impl Future<Output = Result<Signature, SignError>> + Send;
+}
+
+impl<S: Signer + Sync> AsyncSigner for S {
+    fn sign_hash_async(
+        &self,
+        message: &Bytes32,
+    ) -> impl Future<Output = Result<Signature, SignError>> + Send {
+        std::future::ready(self.sign_hash(message))
+    }
+}
+
+/// Returns a reserved receipt to its pool when dropped, unless it was handed
+/// out. This is what keeps a cancelled `commit_async` from leaking the receipt.
+struct Reservation<F: FnMut(&ReceiptId)> {
+    receipt_id: ReceiptId,
+    cancel: Option<F>,
+}
+
+impl<F: FnMut(&ReceiptId)> Reservation<F> {
+    fn keep(mut self) {
+        self.cancel = None;
+    }
+}
+
+impl<F: FnMut(&ReceiptId)> Drop for Reservation<F> {
+    fn drop(&mut self) {
+        if let Some(mut cancel) = self.cancel.take() {
+            cancel(&self.receipt_id);
+        }
+    }
+}
+
+impl ReceiptPool {
+    /// Like `commit`, but awaits the signer. If the future is dropped before
+    /// it completes, the receipt goes back to the pool.
+    pub async fn commit_async(
+        &mut self,
+        signer: &impl AsyncSigner,
+        locked_fee: U256,
+    ) -> Result<Vec<u8>, BorrowFail> {
+        let (allocation, signing_scheme) = (self.allocation, self.signing_scheme);
+        let receipt = self.reserve(locked_fee);
+        let reservation = Reservation {
+            receipt_id: receipt.receipt_id,
+            cancel: Some(|receipt_id: &ReceiptId| self.cancel(receipt_id)),
+        };
+
+        let message = commitment_message(&allocation, &receipt, locked_fee, &signing_scheme);
+        let signature = signer.sign_hash_async(&message).await?;
+        reservation.keep();
+        Ok(write_commitment(
+            &allocation,
+            &receipt,
+            locked_fee,
+            &signature,
+        ))
+    }
+}
+
+impl ConcurrentReceiptPool {
+    /// Like `commit`, but awaits the signer. The lock is not held while
+    /// waiting. If the future is dropped before it completes, the receipt
+    /// goes back to the pool.
+    pub async fn commit_async(
+        &self,
+        signer: &impl AsyncSigner,
+        locked_fee: U256,
+    ) -> Result<Vec<u8>, BorrowFail> {
+        let (receipt, signing_scheme) = {
+            let mut pool = self.lock();
+            (pool.reserve(locked_fee), pool.signing_scheme)
+        };
+        let reservation = Reservation {
+            receipt_id: receipt.receipt_id,
+            cancel: Some(|receipt_id: &ReceiptId| self.lock().cancel(receipt_id)),
+        };
+
+        let message = commitment_message(self.allocation(), &receipt, locked_fee, &signing_scheme);
+        let signature = signer.sign_hash_async(&message).await?;
+        reservation.keep();
+        Ok(write_commitment(
+            self.allocation(),
+            &receipt,
+            locked_fee,
+            &signature,
+        ))
+    }
+}
+
+pub async fn receipts_to_voucher_async(
+    allocation_id: &Address,
+    allocation_signer: &impl VerifyingKey,
+    voucher_signer: &impl AsyncSigner,
+    data: &[u8],
+) -> Result<Voucher, VoucherError> {
+    VoucherConfig::default()
+        .receipts_to_voucher_async(allocation_id, allocation_signer, voucher_signer, data)
+        .await
+}
+
+pub async fn receipts_to_partial_voucher_async(
+    allocation_id: &Address,
+    allocation_signer: &impl VerifyingKey,
+    voucher_signer: &impl AsyncSigner,
+    data: &[u8],
+) -> Result<PartialVoucher, VoucherError> {
+    VoucherConfig::default()
+        .receipts_to_partial_voucher_async(allocation_id, allocation_signer, voucher_signer, data)
+        .await
+}
+
+pub async fn combine_partial_vouchers_with_signers_async(
+    allocation_id: &Address,
+    partial_voucher_signers: &[impl VerifyingKey],
+    voucher_signer: &impl AsyncSigner,
+    partial_vouchers: &[PartialVoucher],
+) -> Result<Voucher, VoucherError> {
+    VoucherConfig::default()
+        .combine_partial_vouchers_with_signers_async(
+            allocation_id,
+            partial_voucher_signers,
+            voucher_signer,
+            partial_vouchers,
+        )
+        .await
+}
+
+impl VoucherConfig {
+    pub async fn receipts_to_voucher_async(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        voucher_signer: &impl AsyncSigner,
+        data: &[u8],
+    ) -> Result<Voucher, VoucherError> {
+        let fees = self.verify_receipts(allocation_id, allocation_signer, data)?;
+        self.sign_voucher_async(allocation_id, fees, voucher_signer)
+            .await
+    }
+
+    pub async fn receipts_to_partial_voucher_async(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        voucher_signer: &impl AsyncSigner,
+        data: &[u8],
+    ) -> Result<PartialVoucher, VoucherError> {
+        let fees = self.verify_receipts(allocation_id, allocation_signer, data)?;
+        let (receipt_id_min, receipt_id_max) = receipt_id_bounds(data)?;
+        self.sign_partial_voucher_async(
+            allocation_id,
+            fees,
+            receipt_id_min,
+            receipt_id_max,
+            voucher_signer,
+        )
+        .await
+    }
+
+    /// Like `combine_partial_vouchers_with_signers`. The partial voucher
+    /// signers have to be given, since the key behind an `AsyncSigner` isn't
+    /// known here.
+    pub async fn combine_partial_vouchers_with_signers_async(
+        &self,
+        allocation_id: &Address,
+        partial_voucher_signers: &[impl VerifyingKey],
+        voucher_signer: &impl AsyncSigner,
+        partial_vouchers: &[PartialVoucher],
+    ) -> Result<Voucher, VoucherError> {
+        let fees =
+            self.verify_partial_vouchers(allocation_id, partial_voucher_signers, partial_vouchers)?;
+        self.sign_voucher_async(allocation_id, fees, voucher_signer)
+            .await
+    }
+
+    async fn sign_voucher_async(
+        &self,
+        allocation_id: &Address,
+        fees: U256,
+        voucher_signer: &impl AsyncSigner,
+    ) -> Result<Voucher, VoucherError> {
+        let message = self.signing_scheme.voucher_hash(allocation_id, fees);
+        Ok(Voucher {
+            allocation_id: *allocation_id,
+            fees,
+            signature: voucher_signer.sign_hash_async(&message).await?,
+        })
+    }
+
+    async fn sign_partial_voucher_async(
+        &self,
+        allocation_id: &Address,
+        fees: U256,
+        receipt_id_min: ReceiptId,
+        receipt_id_max: ReceiptId,
+        voucher_signer: &impl AsyncSigner,
+    ) -> Result<PartialVoucher, VoucherError> {
+        let message = self.signing_scheme.partial_voucher_hash(
+            allocation_id,
+            fees,
+            &receipt_id_min,
+            &receipt_id_max,
+        );
+        Ok(PartialVoucher {
+            voucher: Voucher {
+                allocation_id: *allocation_id,
+                fees,
+                signature: voucher_signer.sign_hash_async(&message).await?,
+            },
+            receipt_id_min,
+            receipt_id_max,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, FutureExt as _};
+
+    use super::*;
+    use crate::{tests::*, *};
+
+    /// A remote signer which never responds.
+    struct HangingSigner;
+
+    impl AsyncSigner for HangingSigner {
+        fn sign_hash_async(
+            &self,
+            _: &Bytes32,
+        ) -> impl Future<Output = Result<Signature, SignError>> + Send {
+            futures::future::pending()
+        }
+    }
+
+    fn assert_send<T: Send>(value: T) -> T {
+        value
+    }
+
+    #[test]
+    fn commit_async_matches_commit() {
+        let allocation_id = bytes(1);
+        let mut pool = ReceiptPool::new(allocation_id);
+        let first = block_on(pool.commit_async(&test_signer(), U256::from(3))).unwrap();
+        pool.release(&first, QueryStatus::Success).unwrap();
+
+        let pool = ConcurrentReceiptPool::from(pool);
+        let second = block_on(assert_send(
+            pool.commit_async(&test_signer(), U256::from(4)),
+        ))
+        .unwrap();
+        let verifier = ReceiptVerifier::new(allocation_id, test_signer_address());
+        let receipt = verifier.verify(&second).unwrap();
+        assert_eq!(receipt.fee, U256::from(7));
+        assert_eq!(receipt.unlocked_fee, U256::from(3));
+
+        let signer = ExternalSigner::new(test_signer());
+        signer.set_available(false);
+        assert_eq!(
+            block_on(pool.commit_async(&signer, U256::from(1))),
+            Err(BorrowFail::SignerFailure("signer unavailable".to_string()))
+        );
+        assert_eq!(pool.lock().outstanding().count(), 1);
+    }
+
+    #[test]
+    fn cancelled_commit_returns_receipt() {
+        let mut pool = ReceiptPool::new(bytes(1));
+        let first = pool.commit(&test_signer(), U256::from(3)).unwrap();
+        pool.release(&first, QueryStatus::Success).unwrap();
+
+        // Polled once, then dropped while waiting on the signer
+        assert_eq!(
+            pool.commit_async(&HangingSigner, U256::from(1))
+                .now_or_never(),
+            None
+        );
+        assert_eq!(pool.outstanding().count(), 0);
+        assert_eq!(pool.unlocked_fees(), U256::from(3));
+
+        let pool = ConcurrentReceiptPool::from(pool);
+        assert_eq!(
+            pool.commit_async(&HangingSigner, U256::from(1))
+                .now_or_never(),
+            None
+        );
+        let pool = pool.into_inner();
+        assert_eq!(pool.outstanding().count(), 0);
+        assert_eq!(pool.unlocked_fees(), U256::from(3));
+    }
+
+    #[test]
+    fn async_vouchers_match_sync() {
+        let allocation_id = bytes(1);
+        let receipts = create_receipts(allocation_id, 6);
+
+        // Warning: This is relying on an ECDSA implementation compatible with RFC 6979
+        // (deterministic usage of signatures).
+        let partial_vouchers: Vec<PartialVoucher> = receipts
+            .chunks(112 * 3)
+            .map(|receipts| {
+                let partial_voucher = block_on(receipts_to_partial_voucher_async(
+                    &allocation_id,
+                    &test_signer_address(),
+                    &test_signer(),
+                    receipts,
+                ))
+                .unwrap();
+                assert_eq!(
+                    Ok(&partial_voucher),
+                    receipts_to_partial_voucher(
+                        &allocation_id,
+                        &test_signer_address(),
+                        &test_signer(),
+                        receipts
+                    )
+                    .as_ref()
+                );
+                partial_voucher
+            })
+            .collect();
+
+        let voucher = block_on(receipts_to_voucher_async(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &receipts,
+        ))
+        .unwrap();
+        assert_eq!(
+            Ok(&voucher),
+            receipts_to_voucher(
+                &allocation_id,
+                &test_signer_address(),
+                &test_signer(),
+                &receipts
+            )
+            .as_ref()
+        );
+        assert_eq!(
+            block_on(combine_partial_vouchers_with_signers_async(
+                &allocation_id,
+                &[test_signer_address()],
+                &test_signer(),
+                &partial_vouchers,
+            )),
+            Ok(voucher)
+        );
+
+        let signer = ExternalSigner::new(test_signer());
+        signer.set_available(false);
+        assert_eq!(
+            block_on(receipts_to_voucher_async(
+                &allocation_id,
+                &test_signer_address(),
+                &signer,
+                &receipts,
+            )),
+            Err(VoucherError::SignerFailure(
+                "signer unavailable".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{
+    prelude::*,
+    voucher::{Receipt, SIZE},
+    PartialVoucher, Voucher, VoucherConfig, VoucherError,
+};
+
+/// Builds a voucher from receipts which are added one at a time or in chunks,
+/// for when they can't all be held in a single buffer for
+/// `receipts_to_voucher`. Receipts are verified as they are added, and must be
+/// added in ascending order of receipt id across all chunks.
+pub struct VoucherBuilder<'k, K> {
+    config: VoucherConfig,
+    allocation_id: Address,
+    allocation_signer: &'k K,
+    fees: U256,
+    count: usize,
+    receipt_id_min: Option<ReceiptId>,
+    receipt_id_max: Option<ReceiptId>,
+}
+
+impl<'k, K: VerifyingKey> VoucherBuilder<'k, K> {
+    pub fn new(allocation_id: Address, allocation_signer: &'k K) -> Self {
+        Self {
+            config: VoucherConfig::default(),
+            allocation_id,
+            allocation_signer,
+            fees: U256::zero(),
+            count: 0,
+            receipt_id_min: None,
+            receipt_id_max: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: VoucherConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The number of receipts added so far.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn fees(&self) -> U256 {
+        self.fees
+    }
+
+    /// Adds a single receipt, in the 112 byte format accepted by
+    /// `receipts_to_voucher`. The builder is unchanged if this fails.
+    pub fn push(&mut self, receipt: &[u8]) -> Result<(), VoucherError> {
+        if receipt.len() != SIZE {
+            return Err(VoucherError::InvalidData(None));
+        }
+        let receipt = Receipt::new(receipt);
+        // Indexed by the number of receipts added before this one.
+        let item = receipt.batch_item(self.count);
+
+        if matches!(self.receipt_id_max, Some(max) if &max >= receipt.id) {
+            return Err(VoucherError::UnorderedReceipts(item));
+        }
+        self.config
+            .verify_signature(&self.allocation_id, self.allocation_signer, &receipt)
+            .map_err(|err| err.at(item))?;
+        self.fees = self
+            .config
+            .add_fees(self.fees, receipt.fees)
+            .ok_or(VoucherError::VoucherValueTooLarge(item))?;
+
+        self.count += 1;
+        self.receipt_id_min.get_or_insert(*receipt.id);
+        self.receipt_id_max = Some(*receipt.id);
+        Ok(())
+    }
+
+    /// Adds concatenated receipts. If this fails, the receipts in the chunk
+    /// before the failing one will have been added.
+    pub fn extend(&mut self, data: &[u8]) -> Result<(), VoucherError> {
+        if !data.len().is_multiple_of(SIZE) {
+            return Err(VoucherError::InvalidData(None));
+        }
+        for receipt in data.chunks(SIZE) {
+            self.push(receipt)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish_voucher(self, voucher_signer: &impl Signer) -> Result<Voucher, VoucherError> {
+        // The contract will revert if this is 0
+        if self.fees == U256::zero() {
+            return Err(VoucherError::NoValue);
+        }
+        self.config
+            .sign_voucher(&self.allocation_id, self.fees, voucher_signer)
+    }
+
+    pub fn finish_partial_voucher(
+        self,
+        voucher_signer: &impl Signer,
+    ) -> Result<PartialVoucher, VoucherError> {
+        if self.fees == U256::zero() {
+            return Err(VoucherError::NoValue);
+        }
+        self.config.sign_partial_voucher(
+            &self.allocation_id,
+            self.fees,
+            self.receipt_id_min.unwrap(),
+            self.receipt_id_max.unwrap(),
+            voucher_signer,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::PublicKey;
+
+    use super::*;
+    use crate::{receipts_to_partial_voucher, receipts_to_voucher, tests::*};
+
+    #[test]
+    fn matches_receipts_to_voucher() {
+        let allocation_id = bytes(1);
+        let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+        let receipts = create_receipts(allocation_id, 50);
+
+        let mut one_at_a_time = VoucherBuilder::new(allocation_id, &allocation_signer);
+        for receipt in receipts.chunks(SIZE) {
+            one_at_a_time.push(receipt).unwrap();
+        }
+        let mut chunked = VoucherBuilder::new(allocation_id, &allocation_signer);
+        for chunk in receipts.chunks(SIZE * 7) {
+            chunked.extend(chunk).unwrap();
+        }
+        assert_eq!(chunked.len(), 50);
+        assert_eq!(chunked.fees(), U256::from(50));
+
+        // Warning: This is relying on an ECDSA implementation compatible with RFC 6979
+        // (deterministic usage of signatures).
+        assert_eq!(
+            one_at_a_time.finish_voucher(&test_signer()),
+            receipts_to_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                &receipts
+            )
+        );
+        assert_eq!(
+            chunked.finish_partial_voucher(&test_signer()),
+            receipts_to_partial_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                &receipts
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_bad_receipts() {
+        let allocation_id = bytes(1);
+        let receipts = create_receipts(allocation_id, 4);
+        let signer = test_signer_address();
+        let item =
+            |index, receipt: usize| Receipt::new(&receipts[SIZE * receipt..]).batch_item(index);
+
+        let builder = VoucherBuilder::new(allocation_id, &signer);
+
+        assert_eq!(
+            builder.finish_partial_voucher(&test_signer()),
+            Err(VoucherError::NoValue)
+        );
+
+        let mut builder = VoucherBuilder::new(allocation_id, &signer);
+        builder.extend(&receipts[SIZE * 2..]).unwrap();
+        // Ordering is enforced across chunks
+        assert_eq!(
+            builder.extend(&receipts[..SIZE * 2]),
+            Err(VoucherError::UnorderedReceipts(item(2, 0)))
+        );
+        assert_eq!(
+            builder.push(&receipts[SIZE * 3..]),
+            Err(VoucherError::UnorderedReceipts(item(2, 3)))
+        );
+        assert_eq!(
+            builder.extend(&receipts[1..]),
+            Err(VoucherError::InvalidData(None))
+        );
+
+        let mut builder = VoucherBuilder::new(bytes(2), &signer);
+        assert_eq!(
+            builder.push(&receipts[..SIZE]),
+            Err(VoucherError::InvalidSignature(Some(item(0, 0))))
+        );
+        assert!(builder.is_empty());
+    }
+}
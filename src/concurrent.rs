@@ -0,0 +1,129 @@
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{pool::commitment, prelude::*, BorrowFail, QueryStatus, ReceiptPool, ReleaseError};
+
+/// A `ReceiptPool` which can be shared between threads. The lock is only held
+/// while selecting or returning a receipt. Signing happens outside of it.
+#[derive(Debug)]
+pub struct ConcurrentReceiptPool {
+    allocation: Address,
+    pool: Mutex<ReceiptPool>,
+}
+
+impl From<ReceiptPool> for ConcurrentReceiptPool {
+    fn from(pool: ReceiptPool) -> Self {
+        Self {
+            allocation: pool.allocation,
+            pool: Mutex::new(pool),
+        }
+    }
+}
+
+impl ConcurrentReceiptPool {
+    pub fn new(allocation: Address) -> Self {
+        ReceiptPool::new(allocation).into()
+    }
+
+    pub fn allocation(&self) -> &Address {
+        &self.allocation
+    }
+
+    /// Locks the underlying pool, e.g. to inspect or expire outstanding receipts.
+    pub fn lock(&self) -> MutexGuard<'_, ReceiptPool> {
+        // The pool is never left in an inconsistent state by a panic, since
+        // nothing that can panic happens while the lock is held.
+        self.pool.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn into_inner(self) -> ReceiptPool {
+        self.pool
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn commit(&self, signer: &impl Signer, locked_fee: U256) -> Result<Vec<u8>, BorrowFail> {
+        let (receipt, signing_scheme) = {
+            let mut pool = self.lock();
+            (pool.reserve(locked_fee), pool.signing_scheme)
+        };
+        let result = commitment(
+            &self.allocation,
+            &receipt,
+            locked_fee,
+            &signing_scheme,
+            signer,
+        );
+        if result.is_err() {
+            self.lock().cancel(&receipt.receipt_id);
+        }
+        result
+    }
+
+    pub fn release(&self, bytes: &[u8], status: QueryStatus) -> Result<(), ReleaseError> {
+        self.lock().release(bytes, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use super::*;
+    use crate::{tests::*, BorrowedReceipt};
+
+    const THREADS: usize = 8;
+
+    fn receipt_id(bytes: &[u8]) -> ReceiptId {
+        *BorrowedReceipt::try_from(bytes).unwrap().receipt_id()
+    }
+
+    #[test]
+    fn concurrent_commits_are_unique() {
+        let pool = ConcurrentReceiptPool::new(bytes(1));
+
+        let borrows: Vec<Vec<u8>> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..50)
+                            .map(|_| pool.commit(&test_signer(), U256::from(1)).unwrap())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        });
+
+        let ids: HashSet<ReceiptId> = borrows.iter().map(|b| receipt_id(b)).collect();
+        assert_eq!(ids.len(), THREADS * 50);
+        assert_eq!(pool.lock().outstanding().count(), THREADS * 50);
+    }
+
+    #[test]
+    fn receipts_are_never_outstanding_twice() {
+        let pool = ConcurrentReceiptPool::new(bytes(1));
+        let in_flight = Mutex::new(HashSet::<ReceiptId>::new());
+
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let borrow = pool.commit(&test_signer(), U256::from(1)).unwrap();
+                        let id = receipt_id(&borrow);
+                        assert!(in_flight.lock().unwrap().insert(id), "Receipt id reused");
+                        thread::yield_now();
+                        assert!(in_flight.lock().unwrap().remove(&id));
+                        pool.release(&borrow, QueryStatus::Success).unwrap();
+                    }
+                });
+            }
+        });
+
+        let pool = pool.into_inner();
+        assert_eq!(pool.outstanding().count(), 0);
+        assert_eq!(pool.unlocked_fees(), U256::from(THREADS * 100));
+    }
+}
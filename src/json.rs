@@ -0,0 +1,224 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{prelude::*, PartialVoucher, Voucher, VoucherError};
+
+// The canonical JSON shape of vouchers. Byte strings are 0x-prefixed hex, and
+// are rejected without the prefix. Fees are written in decimal, but may also
+// be given as 0x-prefixed hex. Either way they need at least one digit.
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VoucherJson {
+    allocation_id: String,
+    fees: String,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PartialVoucherJson {
+    allocation_id: String,
+    fees: String,
+    signature: String,
+    receipt_id_min: String,
+    receipt_id_max: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+fn from_hex<const N: usize>(field: &str, value: &str) -> Result<[u8; N], String> {
+    let hex = value
+        .strip_prefix("0x")
+        .ok_or_else(|| format!("invalid {}: missing 0x prefix", field))?;
+    let mut bytes = [0; N];
+    hex::decode_to_slice(hex, &mut bytes).map_err(|err| format!("invalid {}: {}", field, err))?;
+    Ok(bytes)
+}
+
+fn parse_fees(value: &str) -> Result<U256, String> {
+    match value.strip_prefix("0x") {
+        // Both parsers would take an empty string as 0.
+        Some("") => Err("no digits".to_string()),
+        None if value.is_empty() => Err("no digits".to_string()),
+        Some(hex) => U256::from_str_radix(hex, 16).map_err(|err| err.to_string()),
+        None => U256::from_dec_str(value).map_err(|err| err.to_string()),
+    }
+    .map_err(|err| format!("invalid fees: {}", err))
+}
+
+impl From<&Voucher> for VoucherJson {
+    fn from(voucher: &Voucher) -> Self {
+        Self {
+            allocation_id: to_hex(&voucher.allocation_id),
+            fees: voucher.fees.to_string(),
+            signature: to_hex(&voucher.signature),
+        }
+    }
+}
+
+impl TryFrom<VoucherJson> for Voucher {
+    type Error = String;
+    fn try_from(json: VoucherJson) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allocation_id: from_hex("allocation_id", &json.allocation_id)?,
+            fees: parse_fees(&json.fees)?,
+            signature: from_hex("signature", &json.signature)?,
+        })
+    }
+}
+
+impl From<&PartialVoucher> for PartialVoucherJson {
+    fn from(partial_voucher: &PartialVoucher) -> Self {
+        let voucher = VoucherJson::from(&partial_voucher.voucher);
+        Self {
+            allocation_id: voucher.allocation_id,
+            fees: voucher.fees,
+            signature: voucher.signature,
+            receipt_id_min: to_hex(&partial_voucher.receipt_id_min),
+            receipt_id_max: to_hex(&partial_voucher.receipt_id_max),
+        }
+    }
+}
+
+impl TryFrom<PartialVoucherJson> for PartialVoucher {
+    type Error = String;
+    fn try_from(json: PartialVoucherJson) -> Result<Self, Self::Error> {
+        let voucher = VoucherJson {
+            allocation_id: json.allocation_id,
+            fees: json.fees,
+            signature: json.signature,
+        };
+        Ok(Self {
+            voucher: voucher.try_into()?,
+            receipt_id_min: from_hex("receipt_id_min", &json.receipt_id_min)?,
+            receipt_id_max: from_hex("receipt_id_max", &json.receipt_id_max)?,
+        })
+    }
+}
+
+impl Serialize for Voucher {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        VoucherJson::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Voucher {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        VoucherJson::deserialize(deserializer)?
+            .try_into()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for PartialVoucher {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PartialVoucherJson::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PartialVoucher {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        PartialVoucherJson::deserialize(deserializer)?
+            .try_into()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Voucher {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, VoucherError> {
+        serde_json::from_str(json).map_err(|err| VoucherError::JsonDeserialization(err.to_string()))
+    }
+}
+
+impl PartialVoucher {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, VoucherError> {
+        serde_json::from_str(json).map_err(|err| VoucherError::JsonDeserialization(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    fn test_partial_voucher() -> PartialVoucher {
+        PartialVoucher {
+            voucher: Voucher {
+                allocation_id: bytes(0xab),
+                fees: U256::from(1_000_000_007u64),
+                signature: bytes(0x11),
+            },
+            receipt_id_min: bytes(1),
+            receipt_id_max: bytes(2),
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let partial_voucher = test_partial_voucher();
+        let voucher = partial_voucher.voucher.clone();
+
+        let json = voucher.to_json();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"allocation_id":"0x{}","fees":"1000000007","signature":"0x{}"}}"#,
+                "ab".repeat(20),
+                "11".repeat(65),
+            )
+        );
+        assert_eq!(Voucher::from_json(&json), Ok(voucher));
+
+        let json = partial_voucher.to_json();
+        assert!(json.ends_with(&format!(
+            r#""receipt_id_min":"0x{}","receipt_id_max":"0x{}"}}"#,
+            "01".repeat(15),
+            "02".repeat(15),
+        )));
+        assert_eq!(PartialVoucher::from_json(&json), Ok(partial_voucher));
+    }
+
+    #[test]
+    fn json_accepts_hex_fees() {
+        let json = format!(
+            r#"{{"allocation_id":"0x{}","fees":"0x3b9aca07","signature":"0x{}"}}"#,
+            "ab".repeat(20),
+            "11".repeat(65),
+        );
+        assert_eq!(
+            Voucher::from_json(&json),
+            Ok(test_partial_voucher().voucher)
+        );
+    }
+
+    #[test]
+    fn json_rejects_bad_input() {
+        let valid = test_partial_voucher().voucher.to_json();
+        for json in [
+            "{}".to_string(),
+            "not json".to_string(),
+            valid.replace("1000000007", "-1"),
+            valid.replace("1000000007", "0xzz"),
+            valid.replace("1000000007", ""),
+            valid.replace("1000000007", "0x"),
+            valid.replace(&format!("0x{}", "ab".repeat(20)), &"ab".repeat(20)),
+            valid.replace(&"ab".repeat(20), &"ab".repeat(19)),
+            valid.replace(&"11".repeat(65), &"1g".repeat(65)),
+        ] {
+            assert!(matches!(
+                Voucher::from_json(&json),
+                Err(VoucherError::JsonDeserialization(_))
+            ));
+        }
+    }
+}
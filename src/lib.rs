@@ -1,11 +1,43 @@
-pub use pool::{BorrowFail, QueryStatus, ReceiptPool};
+#[cfg(feature = "async")]
+pub use asynchronous::{
+    combine_partial_vouchers_with_signers_async, receipts_to_partial_voucher_async,
+    receipts_to_voucher_async, AsyncSigner,
+};
+pub use builder::VoucherBuilder;
+pub use concurrent::ConcurrentReceiptPool;
+pub use manager::PoolManager;
+pub use pool::{
+    BorrowFail, OutstandingReceipt, QueryStatus, ReceiptPool, ReleaseError, RetiredReceipt,
+    SelectionStrategy,
+};
+pub use receipt::{
+    BorrowedReceipt, ReceiptError, ReceiptVerifier, VerifiedReceipt, BORROWED_RECEIPT_LEN,
+};
+pub use scheme::{Eip712Domain, SigningScheme};
+pub use signer::{public_key_address, SignError, Signer, VerifyError, VerifyingKey};
+pub use snapshot::SnapshotError;
 pub use voucher::{
-    combine_partial_vouchers, receipts_to_partial_voucher, receipts_to_voucher, PartialVoucher,
-    Voucher, VoucherError,
+    collapse_receipts, combine_partial_vouchers, combine_partial_vouchers_with_signers,
+    merge_partial_vouchers, merge_partial_vouchers_with_signers, partition_receipts,
+    receipts_to_partial_voucher, receipts_to_partial_voucher_lenient, receipts_to_voucher,
+    receipts_to_voucher_lenient, sort_receipts, BatchItem, CollapsedReceipts, ExcludedReceipt,
+    ExclusionReason, ExclusionReport, PartialVoucher, ReceiptPartition, Voucher, VoucherConfig,
+    VoucherError, PARTIAL_VOUCHER_LEN, VOUCHER_LEN,
 };
 
+#[cfg(feature = "async")]
+mod asynchronous;
+mod builder;
+mod concurrent;
+#[cfg(feature = "serde")]
+mod json;
+mod manager;
 mod pool;
 mod prelude;
+mod receipt;
+mod scheme;
+mod signer;
+mod snapshot;
 mod voucher;
 
 #[cfg(test)]
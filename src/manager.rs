@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use crate::{prelude::*, BorrowFail, BorrowedReceipt, QueryStatus, ReceiptPool, ReleaseError};
+
+/// Holds a `ReceiptPool` for each open allocation, grouped by indexer.
+#[derive(Debug, Default)]
+pub struct PoolManager {
+    pools: HashMap<Address, ReceiptPool>,
+    /// Allocations of each indexer, in the order they were added.
+    allocations: HashMap<Address, Vec<Address>>,
+}
+
+impl PoolManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an empty pool for the allocation. Returns false if the
+    /// allocation was already present.
+    pub fn add_allocation(&mut self, indexer: Address, allocation: Address) -> bool {
+        self.insert_pool(indexer, ReceiptPool::new(allocation))
+    }
+
+    /// Adds a pool which has already been set up, e.g. restored from a
+    /// snapshot or configured with a signing scheme. Returns false, leaving
+    /// the existing pool in place, if its allocation was already present.
+    pub fn insert_pool(&mut self, indexer: Address, pool: ReceiptPool) -> bool {
+        let allocation = pool.allocation;
+        if self.pools.contains_key(&allocation) {
+            return false;
+        }
+        self.pools.insert(allocation, pool);
+        self.allocations
+            .entry(indexer)
+            .or_default()
+            .push(allocation);
+        true
+    }
+
+    /// Removes the allocation, returning its pool so that the remaining
+    /// receipts can be collected.
+    pub fn remove_allocation(&mut self, allocation: &Address) -> Option<ReceiptPool> {
+        let pool = self.pools.remove(allocation)?;
+        self.allocations.retain(|_, allocations| {
+            allocations.retain(|a| a != allocation);
+            !allocations.is_empty()
+        });
+        Some(pool)
+    }
+
+    pub fn pool(&self, allocation: &Address) -> Option<&ReceiptPool> {
+        self.pools.get(allocation)
+    }
+
+    pub fn pool_mut(&mut self, allocation: &Address) -> Option<&mut ReceiptPool> {
+        self.pools.get_mut(allocation)
+    }
+
+    /// The allocation that new receipts for the indexer are committed to.
+    /// This is the most recently added one, since older allocations are the
+    /// ones which will be closed first.
+    pub fn select_allocation(&self, indexer: &Address) -> Option<&Address> {
+        self.allocations.get(indexer)?.last()
+    }
+
+    pub fn commit(
+        &mut self,
+        indexer: &Address,
+        signer: &impl Signer,
+        locked_fee: U256,
+    ) -> Result<Vec<u8>, BorrowFail> {
+        let allocation = *self
+            .select_allocation(indexer)
+            .ok_or(BorrowFail::NoAllocation)?;
+        self.pools
+            .get_mut(&allocation)
+            .unwrap()
+            .commit(signer, locked_fee)
+    }
+
+    /// Releases the receipt into the pool of the allocation it was committed to.
+    pub fn release(&mut self, bytes: &[u8], status: QueryStatus) -> Result<(), ReleaseError> {
+        let receipt = BorrowedReceipt::try_from(bytes)
+            .map_err(|_| ReleaseError::InvalidLength(bytes.len()))?;
+        self.pools
+            .get_mut(receipt.allocation_id())
+            .ok_or(ReleaseError::ForeignAllocation)?
+            .release(bytes, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn routes_receipts_by_allocation() {
+        let mut manager = PoolManager::new();
+        let indexer = bytes(10);
+        assert_eq!(
+            manager.commit(&indexer, &test_signer(), U256::from(1)),
+            Err(BorrowFail::NoAllocation)
+        );
+
+        assert!(manager.add_allocation(indexer, bytes(1)));
+        assert!(!manager.add_allocation(indexer, bytes(1)));
+        let old = manager
+            .commit(&indexer, &test_signer(), U256::from(1))
+            .unwrap();
+        assert!(manager.add_allocation(indexer, bytes(2)));
+        assert_eq!(manager.select_allocation(&indexer), Some(&bytes(2)));
+        let new = manager
+            .commit(&indexer, &test_signer(), U256::from(2))
+            .unwrap();
+        let allocation_id = |bytes| *BorrowedReceipt::try_from(bytes).unwrap().allocation_id();
+        assert_eq!(allocation_id(old.as_slice()), bytes(1));
+        assert_eq!(allocation_id(new.as_slice()), bytes(2));
+
+        manager.release(&old, QueryStatus::Success).unwrap();
+        manager.release(&new, QueryStatus::Success).unwrap();
+        assert_eq!(manager.pool(&bytes(1)).unwrap().unlocked_fees(), 1.into());
+        assert_eq!(manager.pool(&bytes(2)).unwrap().unlocked_fees(), 2.into());
+
+        let mut other = ReceiptPool::new(bytes(3));
+        let foreign = other.commit(&test_signer(), U256::from(1)).unwrap();
+        assert_eq!(
+            manager.release(&foreign, QueryStatus::Success),
+            Err(ReleaseError::ForeignAllocation)
+        );
+    }
+
+    #[test]
+    fn routes_receipts_through_inserted_pools() {
+        let mut manager = PoolManager::new();
+        let indexer = bytes(10);
+        let scheme = SigningScheme::Eip712 {
+            domain_separator: bytes(7),
+        };
+        let pool = ReceiptPool::new(bytes(1))
+            .with_signing_scheme(scheme)
+            .with_max_unlocked_fee(U256::from(2));
+        let restored = ReceiptPool::from_snapshot(&pool.to_snapshot()).unwrap();
+        assert!(manager.insert_pool(indexer, pool));
+        assert!(!manager.insert_pool(indexer, restored));
+        assert!(!manager.add_allocation(indexer, bytes(1)));
+
+        let borrow = manager
+            .commit(&indexer, &test_signer(), U256::from(3))
+            .unwrap();
+        let receipt = BorrowedReceipt::try_from(borrow.as_slice()).unwrap();
+        assert_eq!(
+            receipt.recover_signer_with_scheme(&scheme),
+            Ok(test_signer_address())
+        );
+
+        // The pool's value cap still applies.
+        manager.release(&borrow, QueryStatus::Success).unwrap();
+        assert_eq!(manager.pool(&bytes(1)).unwrap().retired().len(), 1);
+    }
+
+    #[test]
+    fn removing_allocations() {
+        let mut manager = PoolManager::new();
+        let indexer = bytes(10);
+        manager.add_allocation(indexer, bytes(1));
+        manager.add_allocation(indexer, bytes(2));
+
+        let pool = manager.remove_allocation(&bytes(2)).unwrap();
+        assert_eq!(pool.allocation, bytes(2));
+        assert_eq!(manager.select_allocation(&indexer), Some(&bytes(1)));
+
+        manager.remove_allocation(&bytes(1)).unwrap();
+        assert_eq!(manager.select_allocation(&indexer), None);
+        assert!(manager.remove_allocation(&bytes(1)).is_none());
+        assert_eq!(
+            manager.commit(&indexer, &test_signer(), U256::from(1)),
+            Err(BorrowFail::NoAllocation)
+        );
+    }
+}
@@ -1,19 +1,12 @@
-use std::fmt;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::Instant,
+};
 
-use rand::RngCore;
-use secp256k1::SecretKey;
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng as _};
 
-use crate::prelude::*;
-
-// Keep track of the offsets to index the data in an array.
-// I'm really happy with how this turned out to make book-keeping easier.
-// A macro might make this better though.
-const ALLOCATION_ID_RANGE: Range = next_range::<Address>(0..0);
-const FEE_RANGE: Range = next_range::<U256>(ALLOCATION_ID_RANGE);
-const RECEIPT_ID_RANGE: Range = next_range::<ReceiptId>(FEE_RANGE);
-const SIGNATURE_RANGE: Range = next_range::<Signature>(RECEIPT_ID_RANGE);
-const UNLOCKED_FEE_RANGE: Range = next_range::<U256>(SIGNATURE_RANGE);
-pub const BORROWED_RECEIPT_LEN: usize = UNLOCKED_FEE_RANGE.end;
+use crate::{prelude::*, receipt::*};
 
 /// A per-allocation collection that can borrow or generate receipts.
 #[derive(Debug, PartialEq, Eq)]
@@ -21,9 +14,83 @@ pub struct ReceiptPool {
     pub allocation: Address,
     /// Receipts that can be folded. These contain an unbroken chain
     /// of agreed upon history between the Indexer and Gateway.
-    receipt_cache: Vec<PooledReceipt>,
+    pub(crate) receipt_cache: VecDeque<PooledReceipt>,
+    /// Receipts which have been committed but not yet released. Receipts
+    /// which did not originate here can't be released into the pool.
+    pub(crate) outstanding: HashMap<ReceiptId, OutstandingReceipt>,
+    /// Chains which reached `max_unlocked_fee`, or the `max_fee` of a capped
+    /// selection strategy, and won't be extended again.
+    pub(crate) retired: Vec<RetiredReceipt>,
+    pub(crate) max_unlocked_fee: U256,
+    pub(crate) signing_scheme: SigningScheme,
+    pub(crate) selection_strategy: SelectionStrategy,
+    /// Generates receipt ids and picks which receipt to reuse.
+    pub(crate) rng: PoolRng,
 }
 
+/// A receipt chain which is no longer extended, because its unlocked fee went
+/// over the pool's maximum. The last receipt on it is ready to be collected
+/// into a voucher.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct RetiredReceipt {
+    pub receipt_id: ReceiptId,
+    pub unlocked_fee: U256,
+}
+
+impl From<&PooledReceipt> for RetiredReceipt {
+    fn from(receipt: &PooledReceipt) -> Self {
+        Self {
+            receipt_id: receipt.receipt_id,
+            unlocked_fee: receipt.unlocked_fee,
+        }
+    }
+}
+
+/// How `ReceiptPool::commit` picks which cached receipt chain to extend.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SelectionStrategy {
+    /// Spreads value evenly across chains.
+    #[default]
+    Random,
+    HighestFee,
+    LowestFee,
+    /// The most recently released receipt first.
+    Lifo,
+    /// The least recently released receipt first.
+    Fifo,
+    /// Random, but only from chains whose fee after the commit would stay
+    /// within `max_fee`, which bounds the value at risk if a commitment is
+    /// lost. A new chain is started if none qualify. Chains which have reached
+    /// `max_fee` are retired, so their value can be collected.
+    Capped {
+        max_fee: U256,
+    },
+}
+
+/// Receipt ids need to be unpredictable, so only a cryptographically secure
+/// RNG will do. It must be `Send + Sync` so pools can still be shared
+/// between threads.
+pub(crate) trait SecureRng: RngCore + CryptoRng + Send + Sync {}
+
+impl<R: RngCore + CryptoRng + Send + Sync> SecureRng for R {}
+
+/// The RNG of a pool, which is ignored when comparing pools.
+pub(crate) struct PoolRng(Box<dyn SecureRng>);
+
+impl fmt::Debug for PoolRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PoolRng")
+    }
+}
+
+impl PartialEq for PoolRng {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for PoolRng {}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum QueryStatus {
     Success,
@@ -37,10 +104,21 @@ pub struct PooledReceipt {
     pub receipt_id: ReceiptId,
 }
 
+/// A receipt which has been handed out by `ReceiptPool::commit` and is
+/// waiting on `ReceiptPool::release`.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct OutstandingReceipt {
+    pub receipt_id: ReceiptId,
+    pub unlocked_fee: U256,
+    pub locked_fee: U256,
+    pub committed_at: Instant,
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub enum BorrowFail {
     NoAllocation,
     InvalidRecoveryId,
+    SignerFailure(String),
 }
 
 impl std::error::Error for BorrowFail {}
@@ -50,6 +128,7 @@ impl fmt::Display for BorrowFail {
         match self {
             Self::NoAllocation => write!(f, "No allocation"),
             Self::InvalidRecoveryId => SignError::InvalidRecoveryId.fmt(f),
+            Self::SignerFailure(err) => write!(f, "Signer failure: {}", err),
         }
     }
 }
@@ -58,6 +137,26 @@ impl From<SignError> for BorrowFail {
     fn from(err: SignError) -> Self {
         match err {
             SignError::InvalidRecoveryId => Self::InvalidRecoveryId,
+            SignError::SignerFailure(err) => Self::SignerFailure(err),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum ReleaseError {
+    InvalidLength(usize),
+    ForeignAllocation,
+    UnknownReceipt,
+}
+
+impl std::error::Error for ReleaseError {}
+
+impl fmt::Display for ReleaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength(len) => ReceiptError::InvalidLength(*len).fmt(f),
+            Self::ForeignAllocation => write!(f, "Receipt is for a different allocation"),
+            Self::UnknownReceipt => write!(f, "Receipt is not outstanding in this pool"),
         }
     }
 }
@@ -67,9 +166,106 @@ impl ReceiptPool {
         Self {
             allocation,
             receipt_cache: Default::default(),
+            outstanding: Default::default(),
+            retired: Default::default(),
+            max_unlocked_fee: U256::MAX,
+            signing_scheme: SigningScheme::Legacy,
+            selection_strategy: SelectionStrategy::Random,
+            rng: PoolRng(Box::new(StdRng::from_entropy())),
         }
     }
 
+    /// Bounds the value at risk on each receipt chain. Once a chain's unlocked
+    /// fee goes over the maximum it is retired, and later commits start new
+    /// chains instead.
+    pub fn with_max_unlocked_fee(mut self, max_unlocked_fee: U256) -> Self {
+        self.max_unlocked_fee = max_unlocked_fee;
+        self
+    }
+
+    pub fn with_selection_strategy(mut self, selection_strategy: SelectionStrategy) -> Self {
+        self.selection_strategy = selection_strategy;
+        self
+    }
+
+    /// Replaces the RNG, which is seeded from the OS by default. A seeded RNG
+    /// makes the pool deterministic, e.g. for tests and simulations.
+    pub fn with_rng(mut self, rng: impl RngCore + CryptoRng + Send + Sync + 'static) -> Self {
+        self.rng = PoolRng(Box::new(rng));
+        self
+    }
+
+    pub fn with_signing_scheme(mut self, signing_scheme: SigningScheme) -> Self {
+        self.signing_scheme = signing_scheme;
+        self
+    }
+
+    /// Receipts which have been committed but not yet released.
+    pub fn outstanding(&self) -> impl Iterator<Item = &OutstandingReceipt> {
+        self.outstanding.values()
+    }
+
+    /// Receipt chains which have been retired and not yet taken.
+    pub fn retired(&self) -> &[RetiredReceipt] {
+        &self.retired
+    }
+
+    /// Hands over the retired receipt chains, e.g. once their receipts have
+    /// been collected into vouchers.
+    pub fn take_retired(&mut self) -> Vec<RetiredReceipt> {
+        std::mem::take(&mut self.retired)
+    }
+
+    /// Fees locked by outstanding receipts, which will either be unlocked or
+    /// returned as collateral when they are released.
+    pub fn locked_fees(&self) -> U256 {
+        self.outstanding
+            .values()
+            .fold(U256::zero(), |sum, r| sum.saturating_add(r.locked_fee))
+    }
+
+    /// Fees unlocked across all receipt chains, including those of
+    /// outstanding receipts and retired chains which have not been taken.
+    pub fn unlocked_fees(&self) -> U256 {
+        self.receipt_cache
+            .iter()
+            .map(|r| r.unlocked_fee)
+            .chain(self.outstanding.values().map(|r| r.unlocked_fee))
+            .chain(self.retired.iter().map(|r| r.unlocked_fee))
+            .fold(U256::zero(), |sum, fee| sum.saturating_add(fee))
+    }
+
+    /// The total value of outstanding receipts (locked and unlocked). This
+    /// is what would be lost if none of them were ever released.
+    pub fn outstanding_collateral(&self) -> U256 {
+        self.outstanding.values().fold(U256::zero(), |sum, r| {
+            sum.saturating_add(r.unlocked_fee)
+                .saturating_add(r.locked_fee)
+        })
+    }
+
+    /// Gives up on receipts committed before the given time, as if they were
+    /// released with `QueryStatus::Unknown`. The expired receipts are returned.
+    pub fn expire_outstanding(&mut self, committed_before: Instant) -> Vec<OutstandingReceipt> {
+        let expired: Vec<ReceiptId> = self
+            .outstanding
+            .values()
+            .filter(|r| r.committed_at < committed_before)
+            .map(|r| r.receipt_id)
+            .collect();
+        expired
+            .into_iter()
+            .map(|receipt_id| {
+                let receipt = self.outstanding.remove(&receipt_id).unwrap();
+                self.return_receipt(PooledReceipt {
+                    unlocked_fee: receipt.unlocked_fee,
+                    receipt_id,
+                });
+                receipt
+            })
+            .collect()
+    }
+
     /// This is only a minimum bound, and doesn't count
     /// outstanding/forgotten receipts which may have account for a
     /// significant portion of unlocked fees
@@ -82,75 +278,232 @@ impl ReceiptPool {
         result
     }
 
-    pub fn commit(&mut self, signer: &SecretKey, locked_fee: U256) -> Result<Vec<u8>, BorrowFail> {
-        let receipt = if self.receipt_cache.is_empty() {
-            let mut receipt_id = ReceiptId::default();
-            rng().fill_bytes(&mut receipt_id);
-            PooledReceipt {
-                receipt_id,
-                unlocked_fee: U256::zero(),
+    pub fn commit(
+        &mut self,
+        signer: &impl Signer,
+        locked_fee: U256,
+    ) -> Result<Vec<u8>, BorrowFail> {
+        let receipt = self.reserve(locked_fee);
+        let result = commitment(
+            &self.allocation,
+            &receipt,
+            locked_fee,
+            &self.signing_scheme,
+            signer,
+        );
+        if result.is_err() {
+            self.cancel(&receipt.receipt_id);
+        }
+        result
+    }
+
+    /// Selects a receipt chain to extend (or starts a new one) and marks it
+    /// as outstanding. This is the only part of committing which needs the
+    /// mutable borrow.
+    pub(crate) fn reserve(&mut self, locked_fee: U256) -> PooledReceipt {
+        let receipt = match self.select(locked_fee) {
+            // Only the ordered strategies care that the rest stay in order.
+            Some(index) => match self.selection_strategy {
+                SelectionStrategy::Lifo | SelectionStrategy::Fifo => {
+                    self.receipt_cache.remove(index).unwrap()
+                }
+                _ => self.receipt_cache.swap_remove_back(index).unwrap(),
+            },
+            None => {
+                let mut receipt_id = ReceiptId::default();
+                self.rng.0.fill_bytes(&mut receipt_id);
+                PooledReceipt {
+                    receipt_id,
+                    unlocked_fee: U256::zero(),
+                }
             }
-        } else {
-            let receipts = &mut self.receipt_cache;
-            let index = rng().gen_range(0..receipts.len());
-            receipts.swap_remove(index)
         };
+        self.outstanding.insert(
+            receipt.receipt_id,
+            OutstandingReceipt {
+                receipt_id: receipt.receipt_id,
+                unlocked_fee: receipt.unlocked_fee,
+                locked_fee,
+                committed_at: Instant::now(),
+            },
+        );
+        receipt
+    }
 
-        // Technically we don't need the mutable borrow from here on out.
-        // If we ever need to unlock more concurency when these are locked
-        // it would be possible to split out the remainder of this method.
-
-        // Write the data in the official receipt that gets sent over the wire.
-        // This is: [allocation_id, fee, receipt_id, signature]
-        let mut commitment = Vec::with_capacity(BORROWED_RECEIPT_LEN);
-        let fee = receipt.unlocked_fee + locked_fee;
-        commitment.extend_from_slice(&self.allocation);
-        commitment.extend_from_slice(&to_be_bytes(fee));
-        commitment.extend_from_slice(&receipt.receipt_id);
-
-        // Engineering in any kind of replay protection like as afforded by EIP-712 is
-        // unnecessary, because the signer key needs to be unique per app. It is a straightforward
-        // extension from there to also say that the signer key should be globally unique and
-        // not sign any messages that are not for the app. Since there are no other structs
-        // to sign, there are no possible collisions.
-        //
-        // The part of the message that needs to be signed in the fee and receipt id only.
-        let signature = sign(
-            &commitment[ALLOCATION_ID_RANGE.start..RECEIPT_ID_RANGE.end],
-            signer,
-        )?;
-        commitment.extend_from_slice(&signature);
+    /// The index of the cached receipt to extend, if any.
+    fn select(&mut self, locked_fee: U256) -> Option<usize> {
+        if let SelectionStrategy::Capped { max_fee } = self.selection_strategy {
+            // Chains which have reached the cap can't be extended by any
+            // commit, so they'd otherwise pile up in the cache.
+            self.retire_chains(|receipt| receipt.unlocked_fee >= max_fee);
+        }
+        let receipts = &self.receipt_cache;
+        if receipts.is_empty() {
+            return None;
+        }
+        let by_fee = || {
+            receipts
+                .iter()
+                .enumerate()
+                .map(|(i, r)| (r.unlocked_fee, i))
+        };
+        match self.selection_strategy {
+            SelectionStrategy::Random => Some(self.rng.0.gen_range(0..receipts.len())),
+            SelectionStrategy::HighestFee => by_fee().max().map(|(_, i)| i),
+            SelectionStrategy::LowestFee => by_fee().min().map(|(_, i)| i),
+            SelectionStrategy::Lifo => Some(receipts.len() - 1),
+            SelectionStrategy::Fifo => Some(0),
+            SelectionStrategy::Capped { max_fee } => {
+                // Chains which only this commit would take over the cap are
+                // skipped, but left for smaller commits.
+                let within: Vec<usize> = receipts
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, r)| within_cap(r, locked_fee, max_fee))
+                    .map(|(i, _)| i)
+                    .collect();
+                if within.is_empty() {
+                    return None;
+                }
+                Some(within[self.rng.0.gen_range(0..within.len())])
+            }
+        }
+    }
 
-        // Extend with the unlocked fee, which is necessary to return collateral
-        // in the case of failure.
-        commitment.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
+    fn retire_chains(&mut self, mut retire: impl FnMut(&PooledReceipt) -> bool) {
+        let retired = &mut self.retired;
+        self.receipt_cache.retain(|receipt| {
+            let retire = retire(receipt);
+            if retire {
+                retired.push(receipt.into());
+            }
+            !retire
+        });
+    }
 
-        debug_assert_eq!(BORROWED_RECEIPT_LEN, commitment.len());
+    /// Puts back a reserved receipt which was never handed out.
+    pub(crate) fn cancel(&mut self, receipt_id: &ReceiptId) {
+        if let Some(receipt) = self.outstanding.remove(receipt_id) {
+            self.return_receipt(PooledReceipt {
+                unlocked_fee: receipt.unlocked_fee,
+                receipt_id: receipt.receipt_id,
+            });
+        }
+    }
 
-        Ok(commitment)
+    /// Makes a receipt chain available to extend again, unless it has gone
+    /// over the maximum unlocked fee.
+    fn return_receipt(&mut self, receipt: PooledReceipt) {
+        if receipt.unlocked_fee > self.max_unlocked_fee {
+            self.retired.push((&receipt).into());
+        } else {
+            self.receipt_cache.push_back(receipt);
+        }
     }
 
-    pub fn release(&mut self, bytes: &[u8], status: QueryStatus) {
-        assert_eq!(bytes.len(), BORROWED_RECEIPT_LEN);
+    pub fn release(&mut self, bytes: &[u8], status: QueryStatus) -> Result<(), ReleaseError> {
+        let receipt = BorrowedReceipt::try_from(bytes)
+            .map_err(|_| ReleaseError::InvalidLength(bytes.len()))?;
+        if receipt.allocation_id() != &self.allocation {
+            return Err(ReleaseError::ForeignAllocation);
+        }
+        // Stale receipts from earlier in the chain are rejected along with
+        // those that were never issued.
+        let outstanding = match self.outstanding.get(receipt.receipt_id()) {
+            Some(outstanding)
+                if outstanding.unlocked_fee == receipt.unlocked_fee()
+                    && outstanding.unlocked_fee + outstanding.locked_fee == receipt.fee() =>
+            {
+                self.outstanding.remove(receipt.receipt_id()).unwrap()
+            }
+            _ => return Err(ReleaseError::UnknownReceipt),
+        };
 
         let unlocked_fee = if status == QueryStatus::Success {
-            U256::from_big_endian(&bytes[FEE_RANGE])
+            outstanding.unlocked_fee + outstanding.locked_fee
         } else {
-            U256::from_big_endian(&bytes[UNLOCKED_FEE_RANGE])
+            outstanding.unlocked_fee
         };
 
         let receipt = PooledReceipt {
             unlocked_fee,
-            receipt_id: bytes[RECEIPT_ID_RANGE].try_into().unwrap(),
+            receipt_id: outstanding.receipt_id,
         };
-        self.receipt_cache.push(receipt);
+        self.return_receipt(receipt);
+        Ok(())
+    }
+}
+
+/// Whether committing `locked_fee` on the chain keeps it within `max_fee`.
+fn within_cap(receipt: &PooledReceipt, locked_fee: U256, max_fee: U256) -> bool {
+    match receipt.unlocked_fee.checked_add(locked_fee) {
+        Some(fee) => fee <= max_fee,
+        None => false,
     }
 }
 
+/// Writes the data in the official receipt that gets sent over the wire for
+/// a reserved receipt.
+pub(crate) fn commitment(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+    signing_scheme: &SigningScheme,
+    signer: &impl Signer,
+) -> Result<Vec<u8>, BorrowFail> {
+    let message = commitment_message(allocation, receipt, locked_fee, signing_scheme);
+    let signature = signer.sign_hash(&message)?;
+    Ok(write_commitment(
+        allocation, receipt, locked_fee, &signature,
+    ))
+}
+
+/// The hash to sign for a reserved receipt.
+pub(crate) fn commitment_message(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+    signing_scheme: &SigningScheme,
+) -> Bytes32 {
+    // Engineering in any kind of replay protection like as afforded by EIP-712 is
+    // unnecessary, because the signer key needs to be unique per app. It is a straightforward
+    // extension from there to also say that the signer key should be globally unique and
+    // not sign any messages that are not for the app. Since there are no other structs
+    // to sign, there are no possible collisions. SigningScheme::Eip712 is available
+    // anyway for those who want the domain separation.
+    //
+    // The part of the message that needs to be signed in the fee and receipt id only.
+    let fee = receipt.unlocked_fee + locked_fee;
+    signing_scheme.receipt_hash(allocation, fee, &receipt.receipt_id)
+}
+
+pub(crate) fn write_commitment(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+    signature: &Signature,
+) -> Vec<u8> {
+    // This is: [allocation_id, fee, receipt_id, signature]
+    let mut commitment = Vec::with_capacity(BORROWED_RECEIPT_LEN);
+    let fee = receipt.unlocked_fee + locked_fee;
+    commitment.extend_from_slice(allocation);
+    commitment.extend_from_slice(&to_be_bytes(fee));
+    commitment.extend_from_slice(&receipt.receipt_id);
+    commitment.extend_from_slice(signature);
+
+    // Extend with the unlocked fee, which is necessary to return collateral
+    // in the case of failure.
+    commitment.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
+
+    debug_assert_eq!(BORROWED_RECEIPT_LEN, commitment.len());
+
+    commitment
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::*;
+    use crate::{tests::*, BorrowedReceipt};
 
     #[track_caller]
     fn assert_successful_borrow(pool: &mut ReceiptPool, fee: impl Into<U256>) -> Vec<u8> {
@@ -158,6 +511,103 @@ mod tests {
             .expect("Should be able to borrow")
     }
 
+    #[test]
+    fn pools_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ReceiptPool>();
+        assert_send_sync::<std::sync::RwLock<ReceiptPool>>();
+        assert_send_sync::<crate::PoolManager>();
+    }
+
+    #[test]
+    fn seeded_pools_are_deterministic() {
+        let mut pools = [
+            ReceiptPool::new(bytes(1)).with_rng(test_rng()),
+            ReceiptPool::new(bytes(1)).with_rng(test_rng()),
+        ];
+        for pool in &mut pools {
+            for fee in 1..=10 {
+                let borrow = assert_successful_borrow(pool, fee);
+                if fee % 3 != 0 {
+                    pool.release(&borrow, QueryStatus::Success).unwrap();
+                }
+            }
+        }
+        let [mut a, mut b] = pools;
+        assert_eq!(a.receipt_cache, b.receipt_cache);
+        assert_eq!(
+            assert_successful_borrow(&mut a, 1),
+            assert_successful_borrow(&mut b, 1)
+        );
+    }
+
+    #[test]
+    fn selection_strategies() {
+        // Chains with unlocked fees of 5, 1, and 3, released in that order
+        let pool = |strategy| {
+            let mut pool = ReceiptPool::new(bytes(1)).with_selection_strategy(strategy);
+            let borrows: Vec<Vec<u8>> = [5, 1, 3]
+                .into_iter()
+                .map(|fee| assert_successful_borrow(&mut pool, fee))
+                .collect();
+            for borrow in &borrows {
+                pool.release(borrow, QueryStatus::Success).unwrap();
+            }
+            pool
+        };
+        let selected = |strategy| {
+            let borrow = assert_successful_borrow(&mut pool(strategy), 1);
+            BorrowedReceipt::try_from(borrow.as_slice())
+                .unwrap()
+                .unlocked_fee()
+                .as_u32()
+        };
+
+        assert!([5, 1, 3].contains(&selected(SelectionStrategy::Random)));
+        assert_eq!(selected(SelectionStrategy::HighestFee), 5);
+        assert_eq!(selected(SelectionStrategy::LowestFee), 1);
+        assert_eq!(selected(SelectionStrategy::Lifo), 3);
+        assert_eq!(selected(SelectionStrategy::Fifo), 5);
+        let capped = |max_fee: u32| {
+            selected(SelectionStrategy::Capped {
+                max_fee: U256::from(max_fee),
+            })
+        };
+        assert!([1, 3].contains(&capped(4)));
+        assert_eq!(capped(2), 1);
+        // A new chain
+        assert_eq!(capped(1), 0);
+
+        // Only chains which have reached the cap are retired. Those which just
+        // don't fit a commit are left for smaller ones.
+        let mut capped_pool = pool(SelectionStrategy::Capped {
+            max_fee: U256::from(4),
+        });
+        assert_successful_borrow(&mut capped_pool, 150);
+        assert_successful_borrow(&mut capped_pool, 2);
+        let retired: Vec<U256> = capped_pool
+            .retired()
+            .iter()
+            .map(|r| r.unlocked_fee)
+            .collect();
+        assert_eq!(retired, [U256::from(5)]);
+        let cached: Vec<U256> = capped_pool
+            .receipt_cache
+            .iter()
+            .map(|r| r.unlocked_fee)
+            .collect();
+        assert_eq!(cached, [U256::from(3)]);
+        assert_eq!(capped_pool.unlocked_fees(), 9.into());
+
+        // The ordered strategies keep their order as receipts are taken.
+        let mut pool = pool(SelectionStrategy::Fifo);
+        for expected in [5, 1, 3] {
+            let borrow = assert_successful_borrow(&mut pool, 0);
+            let receipt = BorrowedReceipt::try_from(borrow.as_slice()).unwrap();
+            assert_eq!(receipt.unlocked_fee(), U256::from(expected));
+        }
+    }
+
     // Simple happy-path case of paying for requests in a loop.
     #[test]
     pub fn can_pay_for_requests() {
@@ -165,7 +615,7 @@ mod tests {
 
         for i in 1..=10 {
             let borrow = assert_successful_borrow(&mut pool, i);
-            pool.release(&borrow, QueryStatus::Success);
+            pool.release(&borrow, QueryStatus::Success).unwrap();
             // Verify that we have unlocked all the fees
             let unlocked: u32 = (0..=i).sum();
             assert_eq!(U256::from(unlocked), pool.known_unlocked_fees());
@@ -183,16 +633,129 @@ mod tests {
         let borrow2 = assert_successful_borrow(&mut pool, 2);
         assert_eq!(pool.known_unlocked_fees(), 0.into());
 
-        pool.release(&borrow3, QueryStatus::Failure);
+        pool.release(&borrow3, QueryStatus::Failure).unwrap();
         assert_eq!(pool.known_unlocked_fees(), 0.into());
 
         let borrow4 = assert_successful_borrow(&mut pool, 4);
         assert_eq!(pool.known_unlocked_fees(), 0.into());
 
-        pool.release(&borrow2, QueryStatus::Success);
+        pool.release(&borrow2, QueryStatus::Success).unwrap();
         assert_eq!(pool.known_unlocked_fees(), 2.into());
 
-        pool.release(&borrow4, QueryStatus::Unknown);
+        pool.release(&borrow4, QueryStatus::Unknown).unwrap();
         assert_eq!(pool.known_unlocked_fees(), 2.into());
     }
+
+    #[test]
+    fn release_rejects_invalid_receipts() {
+        let mut pool = ReceiptPool::new(bytes(3));
+        let mut other = ReceiptPool::new(bytes(3));
+        let mut foreign = ReceiptPool::new(bytes(4));
+
+        let borrow = assert_successful_borrow(&mut pool, 5);
+        assert_eq!(
+            pool.release(&borrow[..100], QueryStatus::Success),
+            Err(ReleaseError::InvalidLength(100))
+        );
+        let from_foreign = assert_successful_borrow(&mut foreign, 5);
+        assert_eq!(
+            pool.release(&from_foreign, QueryStatus::Success),
+            Err(ReleaseError::ForeignAllocation)
+        );
+        let from_other = assert_successful_borrow(&mut other, 5);
+        assert_eq!(
+            pool.release(&from_other, QueryStatus::Success),
+            Err(ReleaseError::UnknownReceipt)
+        );
+        assert_eq!(pool.known_unlocked_fees(), 0.into());
+
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        assert_eq!(pool.known_unlocked_fees(), 5.into());
+    }
+
+    #[test]
+    fn release_rejects_double_release() {
+        let mut pool = ReceiptPool::new(bytes(3));
+
+        let borrow = assert_successful_borrow(&mut pool, 5);
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        assert_eq!(
+            pool.release(&borrow, QueryStatus::Success),
+            Err(ReleaseError::UnknownReceipt)
+        );
+
+        // The stale receipt is rejected even once its chain is outstanding again.
+        let _next = assert_successful_borrow(&mut pool, 1);
+        assert_eq!(
+            pool.release(&borrow, QueryStatus::Success),
+            Err(ReleaseError::UnknownReceipt)
+        );
+        assert_eq!(pool.unlocked_fees(), 5.into());
+    }
+
+    #[test]
+    fn tracks_outstanding_receipts() {
+        let mut pool = ReceiptPool::new(bytes(4));
+
+        let borrow1 = assert_successful_borrow(&mut pool, 1);
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        let borrow3 = assert_successful_borrow(&mut pool, 3);
+        let cutoff = Instant::now();
+        let borrow4 = assert_successful_borrow(&mut pool, 4);
+
+        assert_eq!(pool.outstanding().count(), 2);
+        assert_eq!(pool.locked_fees(), 7.into());
+        assert_eq!(pool.unlocked_fees(), 1.into());
+        assert_eq!(pool.outstanding_collateral(), 8.into());
+
+        let expired = pool.expire_outstanding(cutoff);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].locked_fee, 3.into());
+        assert_eq!(expired[0].unlocked_fee, 1.into());
+        assert_eq!(
+            pool.release(&borrow3, QueryStatus::Success),
+            Err(ReleaseError::UnknownReceipt)
+        );
+        assert_eq!(pool.locked_fees(), 4.into());
+        assert_eq!(pool.unlocked_fees(), 1.into());
+
+        pool.release(&borrow4, QueryStatus::Success).unwrap();
+        assert_eq!(pool.outstanding().count(), 0);
+        assert_eq!(pool.locked_fees(), 0.into());
+        assert_eq!(pool.unlocked_fees(), 5.into());
+        assert_eq!(pool.outstanding_collateral(), 0.into());
+    }
+
+    #[test]
+    fn retires_chains_over_max_unlocked_fee() {
+        let mut pool = ReceiptPool::new(bytes(6)).with_max_unlocked_fee(U256::from(10));
+
+        let borrow = assert_successful_borrow(&mut pool, 8);
+        let receipt_id = *BorrowedReceipt::try_from(borrow.as_slice())
+            .unwrap()
+            .receipt_id();
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        assert!(pool.retired().is_empty());
+
+        let borrow = assert_successful_borrow(&mut pool, 3);
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        assert_eq!(
+            pool.retired(),
+            &[RetiredReceipt {
+                receipt_id,
+                unlocked_fee: 11.into(),
+            }]
+        );
+        assert_eq!(pool.unlocked_fees(), 11.into());
+
+        // The next commit starts a fresh chain.
+        let borrow = assert_successful_borrow(&mut pool, 1);
+        let next = BorrowedReceipt::try_from(borrow.as_slice()).unwrap();
+        assert_ne!(next.receipt_id(), &receipt_id);
+        assert_eq!(next.unlocked_fee(), U256::zero());
+
+        assert_eq!(pool.take_retired().len(), 1);
+        assert!(pool.retired().is_empty());
+        assert_eq!(pool.unlocked_fees(), 0.into());
+    }
 }
@@ -1,10 +1,15 @@
 pub use std::convert::TryInto as _;
-use std::{fmt, mem::size_of};
+use std::mem::size_of;
 
 use lazy_static::lazy_static;
 pub use primitive_types::U256;
-pub use rand::{thread_rng as rng, Rng as _};
-use secp256k1::{Message, Secp256k1, SecretKey};
+pub use rand::Rng as _;
+use secp256k1::Secp256k1;
+
+pub use crate::{
+    scheme::SigningScheme,
+    signer::{SignError, Signer, VerifyError, VerifyingKey},
+};
 
 pub type Bytes32 = [u8; 32];
 pub type Address = [u8; 20];
@@ -38,34 +43,3 @@ pub fn to_be_bytes(value: U256) -> Bytes32 {
     value.to_big_endian(&mut result);
     result
 }
-
-#[derive(Eq, PartialEq, Debug)]
-pub enum SignError {
-    InvalidRecoveryId,
-}
-
-impl fmt::Display for SignError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("Invalid recovery ID")
-    }
-}
-
-pub fn sign(data: &[u8], signer: &SecretKey) -> Result<Signature, SignError> {
-    let message = Message::from_digest_slice(&hash_bytes(data)).unwrap();
-
-    let signature = SECP256K1.sign_ecdsa_recoverable(&message, signer);
-    let (recovery_id, signature) = signature.serialize_compact();
-    let recovery_id = match recovery_id.to_i32() {
-        0 => 27,
-        1 => 28,
-        27 => 27,
-        28 => 28,
-        _ => return Err(SignError::InvalidRecoveryId),
-    };
-
-    let mut serialized = [0; 65];
-    serialized[..64].copy_from_slice(&signature);
-    serialized[64] = recovery_id;
-
-    Ok(serialized)
-}
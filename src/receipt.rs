@@ -0,0 +1,280 @@
+use std::fmt;
+
+use secp256k1::PublicKey;
+
+use crate::{prelude::*, signer::recover_address};
+
+// Keep track of the offsets to index the data in an array.
+// I'm really happy with how this turned out to make book-keeping easier.
+// A macro might make this better though.
+pub(crate) const ALLOCATION_ID_RANGE: Range = next_range::<Address>(0..0);
+pub(crate) const FEE_RANGE: Range = next_range::<U256>(ALLOCATION_ID_RANGE);
+pub(crate) const RECEIPT_ID_RANGE: Range = next_range::<ReceiptId>(FEE_RANGE);
+pub(crate) const SIGNATURE_RANGE: Range = next_range::<Signature>(RECEIPT_ID_RANGE);
+pub(crate) const UNLOCKED_FEE_RANGE: Range = next_range::<U256>(SIGNATURE_RANGE);
+/// Length of a receipt as returned by `ReceiptPool::commit`. 164 bytes.
+pub const BORROWED_RECEIPT_LEN: usize = UNLOCKED_FEE_RANGE.end;
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum ReceiptError {
+    InvalidLength(usize),
+    AllocationMismatch,
+    MalformedSignature,
+    InvalidSignature,
+}
+
+impl std::error::Error for ReceiptError {}
+
+impl fmt::Display for ReceiptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength(len) => write!(
+                f,
+                "Invalid receipt length: expected {} bytes, got {}",
+                BORROWED_RECEIPT_LEN, len
+            ),
+            Self::AllocationMismatch => write!(f, "Receipt is for a different allocation"),
+            Self::MalformedSignature => write!(f, "Malformed receipt signature"),
+            Self::InvalidSignature => write!(f, "Receipt is not signed by the allocation signer"),
+        }
+    }
+}
+
+impl From<VerifyError> for ReceiptError {
+    fn from(err: VerifyError) -> Self {
+        match err {
+            VerifyError::MalformedSignature => Self::MalformedSignature,
+            VerifyError::InvalidSignature => Self::InvalidSignature,
+        }
+    }
+}
+
+/// A zero-copy view over a receipt as returned by `ReceiptPool::commit`.
+/// This is: [allocation_id, fee, receipt_id, signature, unlocked_fee]
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct BorrowedReceipt<'a> {
+    bytes: &'a [u8; BORROWED_RECEIPT_LEN],
+}
+
+impl<'a> TryFrom<&'a [u8]> for BorrowedReceipt<'a> {
+    type Error = ReceiptError;
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| ReceiptError::InvalidLength(bytes.len()))?;
+        Ok(Self { bytes })
+    }
+}
+
+impl<'a> BorrowedReceipt<'a> {
+    pub fn allocation_id(&self) -> &'a Address {
+        self.bytes[ALLOCATION_ID_RANGE].try_into().unwrap()
+    }
+
+    pub fn fee(&self) -> U256 {
+        U256::from_big_endian(&self.bytes[FEE_RANGE])
+    }
+
+    pub fn receipt_id(&self) -> &'a ReceiptId {
+        self.bytes[RECEIPT_ID_RANGE].try_into().unwrap()
+    }
+
+    pub fn signature(&self) -> &'a Signature {
+        self.bytes[SIGNATURE_RANGE].try_into().unwrap()
+    }
+
+    pub fn unlocked_fee(&self) -> U256 {
+        U256::from_big_endian(&self.bytes[UNLOCKED_FEE_RANGE])
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// The [fee, receipt_id, signature] portion that is concatenated into the
+    /// data passed to `receipts_to_voucher`.
+    pub fn voucher_bytes(&self) -> &'a [u8] {
+        &self.bytes[FEE_RANGE.start..SIGNATURE_RANGE.end]
+    }
+
+    /// Recovers the address of the allocation signer.
+    pub fn recover_signer(&self) -> Result<Address, ReceiptError> {
+        self.recover_signer_with_scheme(&SigningScheme::Legacy)
+    }
+
+    pub fn recover_signer_with_scheme(
+        &self,
+        signing_scheme: &SigningScheme,
+    ) -> Result<Address, ReceiptError> {
+        Ok(recover_address(
+            &self.message(signing_scheme),
+            self.signature(),
+        )?)
+    }
+
+    /// The hash covered by the signature.
+    fn message(&self, signing_scheme: &SigningScheme) -> Bytes32 {
+        signing_scheme.receipt_hash(self.allocation_id(), self.fee(), self.receipt_id())
+    }
+}
+
+/// A receipt which has passed verification.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct VerifiedReceipt {
+    pub allocation_id: Address,
+    pub fee: U256,
+    pub receipt_id: ReceiptId,
+    pub signature: Signature,
+    pub unlocked_fee: U256,
+}
+
+/// Checks individual receipts from `ReceiptPool::commit` as they arrive,
+/// without needing the whole batch that `receipts_to_voucher` does.
+/// The allocation signer may be given as either a `PublicKey` or an `Address`.
+#[derive(Debug, Clone)]
+pub struct ReceiptVerifier<K = PublicKey> {
+    allocation_id: Address,
+    allocation_signer: K,
+    signing_scheme: SigningScheme,
+}
+
+impl<K: VerifyingKey> ReceiptVerifier<K> {
+    pub fn new(allocation_id: Address, allocation_signer: K) -> Self {
+        Self {
+            allocation_id,
+            allocation_signer,
+            signing_scheme: SigningScheme::Legacy,
+        }
+    }
+
+    pub fn with_signing_scheme(mut self, signing_scheme: SigningScheme) -> Self {
+        self.signing_scheme = signing_scheme;
+        self
+    }
+
+    pub fn verify(&self, bytes: &[u8]) -> Result<VerifiedReceipt, ReceiptError> {
+        let receipt = BorrowedReceipt::try_from(bytes)?;
+        if receipt.allocation_id() != &self.allocation_id {
+            return Err(ReceiptError::AllocationMismatch);
+        }
+
+        self.allocation_signer
+            .verify_signature(&receipt.message(&self.signing_scheme), receipt.signature())?;
+
+        Ok(VerifiedReceipt {
+            allocation_id: self.allocation_id,
+            fee: receipt.fee(),
+            receipt_id: *receipt.receipt_id(),
+            signature: *receipt.signature(),
+            unlocked_fee: receipt.unlocked_fee(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::SecretKey;
+
+    use super::*;
+    use crate::{tests::*, QueryStatus, ReceiptPool};
+
+    fn verifier(allocation_id: Address) -> ReceiptVerifier {
+        ReceiptVerifier::new(
+            allocation_id,
+            PublicKey::from_secret_key(&SECP256K1, &test_signer()),
+        )
+    }
+
+    #[test]
+    fn verifies_committed_receipts() {
+        let mut pool = ReceiptPool::new(bytes(1));
+        let verifier = verifier(bytes(1));
+
+        let first = pool.commit(&test_signer(), U256::from(3)).unwrap();
+        let receipt = verifier.verify(&first).unwrap();
+        assert_eq!(receipt.allocation_id, bytes(1));
+        assert_eq!(receipt.fee, U256::from(3));
+        assert_eq!(receipt.unlocked_fee, U256::zero());
+
+        pool.release(&first, QueryStatus::Success).unwrap();
+        let second = pool.commit(&test_signer(), U256::from(4)).unwrap();
+        let receipt2 = verifier.verify(&second).unwrap();
+        assert_eq!(receipt2.receipt_id, receipt.receipt_id);
+        assert_eq!(receipt2.fee, U256::from(7));
+        assert_eq!(receipt2.unlocked_fee, U256::from(3));
+    }
+
+    #[test]
+    fn borrowed_receipt_accessors() {
+        let mut pool = ReceiptPool::new(bytes(9));
+        let first = pool.commit(&test_signer(), U256::from(3)).unwrap();
+        pool.release(&first, QueryStatus::Success).unwrap();
+        let second = pool.commit(&test_signer(), U256::from(4)).unwrap();
+
+        let first = BorrowedReceipt::try_from(first.as_slice()).unwrap();
+        let receipt = BorrowedReceipt::try_from(second.as_slice()).unwrap();
+        assert_eq!(receipt.allocation_id(), &bytes(9));
+        assert_eq!(receipt.fee(), U256::from(7));
+        assert_eq!(receipt.receipt_id(), first.receipt_id());
+        assert_eq!(receipt.unlocked_fee(), U256::from(3));
+        assert_eq!(receipt.as_bytes(), second.as_slice());
+        assert_eq!(receipt.voucher_bytes(), &second[20..132]);
+
+        for len in [0, BORROWED_RECEIPT_LEN - 1, BORROWED_RECEIPT_LEN + 1] {
+            let data = vec![0; len];
+            assert_eq!(
+                BorrowedReceipt::try_from(data.as_slice()),
+                Err(ReceiptError::InvalidLength(len))
+            );
+        }
+    }
+
+    #[test]
+    fn verifies_and_recovers_signer_address() {
+        let mut pool = ReceiptPool::new(bytes(1));
+        let commitment = pool.commit(&test_signer(), U256::from(3)).unwrap();
+
+        let receipt = BorrowedReceipt::try_from(commitment.as_slice()).unwrap();
+        assert_eq!(receipt.recover_signer(), Ok(test_signer_address()));
+
+        let verifier = ReceiptVerifier::new(bytes(1), test_signer_address());
+        assert_eq!(verifier.verify(&commitment).unwrap().fee, U256::from(3));
+        let verifier = ReceiptVerifier::new(bytes(1), bytes::<20>(2));
+        assert_eq!(
+            verifier.verify(&commitment),
+            Err(ReceiptError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_receipts() {
+        let mut pool = ReceiptPool::new(bytes(1));
+        let receipt = pool.commit(&test_signer(), U256::from(3)).unwrap();
+
+        assert_eq!(
+            verifier(bytes(1)).verify(&receipt[1..]),
+            Err(ReceiptError::InvalidLength(BORROWED_RECEIPT_LEN - 1))
+        );
+        assert_eq!(
+            verifier(bytes(2)).verify(&receipt),
+            Err(ReceiptError::AllocationMismatch)
+        );
+
+        let mut tampered = receipt.clone();
+        tampered[FEE_RANGE.end - 1] += 1;
+        assert_eq!(
+            verifier(bytes(1)).verify(&tampered),
+            Err(ReceiptError::InvalidSignature)
+        );
+
+        let other_signer = SecretKey::from_slice(&bytes::<32>(7)).unwrap();
+        let verifier = ReceiptVerifier::new(
+            bytes(1),
+            PublicKey::from_secret_key(&SECP256K1, &other_signer),
+        );
+        assert_eq!(
+            verifier.verify(&receipt),
+            Err(ReceiptError::InvalidSignature)
+        );
+    }
+}
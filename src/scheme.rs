@@ -0,0 +1,348 @@
+use lazy_static::lazy_static;
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::prelude::*;
+
+lazy_static! {
+    static ref DOMAIN_TYPE_HASH: Bytes32 = hash_bytes(
+        b"EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)"
+    );
+    static ref RECEIPT_TYPE_HASH: Bytes32 =
+        hash_bytes(b"Receipt(address allocationId,uint256 fees,bytes15 receiptId)");
+    static ref PARTIAL_VOUCHER_TYPE_HASH: Bytes32 = hash_bytes(
+        b"PartialVoucher(address allocationId,uint256 fees,bytes15 receiptIdMin,bytes15 receiptIdMax)"
+    );
+    static ref VOUCHER_TYPE_HASH: Bytes32 =
+        hash_bytes(b"Voucher(address allocationId,uint256 fees)");
+}
+
+/// How the messages for receipts and vouchers are hashed before signing.
+/// Both the signer and verifier must agree on the scheme.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SigningScheme {
+    /// The Keccak hash of the concatenated fields.
+    #[default]
+    Legacy,
+    /// EIP-712 typed structured data, which adds domain separation.
+    Eip712 { domain_separator: Bytes32 },
+}
+
+/// The EIP-712 domain of the contract which redeems vouchers.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Eip712Domain {
+    pub name: String,
+    pub version: String,
+    pub chain_id: U256,
+    pub verifying_contract: Address,
+}
+
+impl Eip712Domain {
+    pub fn separator(&self) -> Bytes32 {
+        hash_struct(
+            &DOMAIN_TYPE_HASH,
+            &[
+                hash_bytes(self.name.as_bytes()),
+                hash_bytes(self.version.as_bytes()),
+                to_be_bytes(self.chain_id),
+                encode_address(&self.verifying_contract),
+            ],
+        )
+    }
+}
+
+impl From<&Eip712Domain> for SigningScheme {
+    fn from(domain: &Eip712Domain) -> Self {
+        Self::Eip712 {
+            domain_separator: domain.separator(),
+        }
+    }
+}
+
+impl SigningScheme {
+    pub(crate) fn receipt_hash(
+        &self,
+        allocation_id: &Address,
+        fees: U256,
+        receipt_id: &ReceiptId,
+    ) -> Bytes32 {
+        match self {
+            Self::Legacy => {
+                // Hashed incrementally rather than with hash_bytes, since this
+                // is done for every receipt in a voucher.
+                let mut hasher = Keccak::v256();
+                hasher.update(allocation_id);
+                hasher.update(&to_be_bytes(fees));
+                hasher.update(receipt_id);
+                let mut message = Bytes32::default();
+                hasher.finalize(&mut message);
+                message
+            }
+            Self::Eip712 { domain_separator } => typed_data_hash(
+                domain_separator,
+                &hash_struct(
+                    &RECEIPT_TYPE_HASH,
+                    &[
+                        encode_address(allocation_id),
+                        to_be_bytes(fees),
+                        encode_fixed_bytes(receipt_id),
+                    ],
+                ),
+            ),
+        }
+    }
+
+    pub(crate) fn partial_voucher_hash(
+        &self,
+        allocation_id: &Address,
+        fees: U256,
+        receipt_id_min: &ReceiptId,
+        receipt_id_max: &ReceiptId,
+    ) -> Bytes32 {
+        match self {
+            Self::Legacy => {
+                let mut message = Vec::new();
+                message.extend_from_slice(allocation_id);
+                message.extend_from_slice(&to_be_bytes(fees));
+                message.extend_from_slice(receipt_id_min);
+                message.extend_from_slice(receipt_id_max);
+                hash_bytes(&message)
+            }
+            Self::Eip712 { domain_separator } => typed_data_hash(
+                domain_separator,
+                &hash_struct(
+                    &PARTIAL_VOUCHER_TYPE_HASH,
+                    &[
+                        encode_address(allocation_id),
+                        to_be_bytes(fees),
+                        encode_fixed_bytes(receipt_id_min),
+                        encode_fixed_bytes(receipt_id_max),
+                    ],
+                ),
+            ),
+        }
+    }
+
+    pub(crate) fn voucher_hash(&self, allocation_id: &Address, fees: U256) -> Bytes32 {
+        match self {
+            Self::Legacy => {
+                let mut message = Vec::new();
+                message.extend_from_slice(allocation_id);
+                message.extend_from_slice(&to_be_bytes(fees));
+                hash_bytes(&message)
+            }
+            Self::Eip712 { domain_separator } => typed_data_hash(
+                domain_separator,
+                &hash_struct(
+                    &VOUCHER_TYPE_HASH,
+                    &[encode_address(allocation_id), to_be_bytes(fees)],
+                ),
+            ),
+        }
+    }
+}
+
+fn hash_struct(type_hash: &Bytes32, fields: &[Bytes32]) -> Bytes32 {
+    let mut hasher = Keccak::v256();
+    hasher.update(type_hash);
+    for field in fields {
+        hasher.update(field);
+    }
+    let mut output = Bytes32::default();
+    hasher.finalize(&mut output);
+    output
+}
+
+fn typed_data_hash(domain_separator: &Bytes32, struct_hash: &Bytes32) -> Bytes32 {
+    let mut hasher = Keccak::v256();
+    hasher.update(b"\x19\x01");
+    hasher.update(domain_separator);
+    hasher.update(struct_hash);
+    let mut output = Bytes32::default();
+    hasher.finalize(&mut output);
+    output
+}
+
+/// Addresses are left-padded to 32 bytes.
+fn encode_address(address: &Address) -> Bytes32 {
+    let mut encoded = Bytes32::default();
+    encoded[12..].copy_from_slice(address);
+    encoded
+}
+
+/// Fixed size byte arrays (bytesN) are right-padded to 32 bytes.
+fn encode_fixed_bytes(bytes: &[u8]) -> Bytes32 {
+    let mut encoded = Bytes32::default();
+    encoded[..bytes.len()].copy_from_slice(bytes);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hex::FromHex as _;
+    use secp256k1::PublicKey;
+
+    use super::*;
+    use crate::{tests::*, *};
+
+    fn hex<const N: usize>(hex: &str) -> [u8; N] {
+        let bytes: Vec<u8> = hex.from_hex().unwrap();
+        bytes.try_into().unwrap()
+    }
+
+    fn test_domain() -> Eip712Domain {
+        Eip712Domain {
+            name: "Receipts".to_string(),
+            version: "1".to_string(),
+            chain_id: U256::from(1337),
+            verifying_contract: bytes(0xaa),
+        }
+    }
+
+    // The "Mail" example from the EIP-712 specification.
+    #[test]
+    fn eip712_spec_example() {
+        let domain = Eip712Domain {
+            name: "Ether Mail".to_string(),
+            version: "1".to_string(),
+            chain_id: U256::one(),
+            verifying_contract: hex("cccccccccccccccccccccccccccccccccccccccc"),
+        };
+        let domain_separator = domain.separator();
+        assert_eq!(
+            domain_separator,
+            hex("f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f")
+        );
+
+        let person_type_hash = hash_bytes(b"Person(string name,address wallet)");
+        let person = |name: &str, wallet: &str| {
+            hash_struct(
+                &person_type_hash,
+                &[hash_bytes(name.as_bytes()), encode_address(&hex(wallet))],
+            )
+        };
+        let mail = hash_struct(
+            &hash_bytes(
+                b"Mail(Person from,Person to,string contents)Person(string name,address wallet)",
+            ),
+            &[
+                person("Cow", "cd2a3d9f938e13cd947ec05abc7fe734df8dd826"),
+                person("Bob", "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"),
+                hash_bytes(b"Hello, Bob!"),
+            ],
+        );
+        assert_eq!(
+            mail,
+            hex("c52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e")
+        );
+        assert_eq!(
+            typed_data_hash(&domain_separator, &mail),
+            hex("be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+        );
+    }
+
+    #[test]
+    fn eip712_known_answers() {
+        let scheme = SigningScheme::from(&test_domain());
+        assert_eq!(
+            scheme,
+            SigningScheme::Eip712 {
+                domain_separator: hex(
+                    "2da501531e57b29bdaa98f2e66530feb820fb9be1045dd3813535c1d94b8b24e"
+                )
+            }
+        );
+        assert_eq!(
+            scheme.receipt_hash(&bytes(1), U256::from(5), &bytes(2)),
+            hex("82abefa8403cbecfa791880422d46a6f7a6747061dc7da6568005b829ab76585")
+        );
+        assert_eq!(
+            scheme.partial_voucher_hash(&bytes(1), U256::from(5), &bytes(2), &bytes(3)),
+            hex("748cb81102692ccbd12686d48aac4e635fbf1b0e3f9d9f693a5eb7ea860d65b6")
+        );
+        assert_eq!(
+            scheme.voucher_hash(&bytes(1), U256::from(5)),
+            hex("195ef5e32e3ba9aa3196f86ac7197849824e33fa3c243dc50affe7faf55b53a3")
+        );
+    }
+
+    #[test]
+    fn eip712_receipts_and_vouchers() {
+        let allocation_id = bytes(1);
+        let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+        let scheme = SigningScheme::from(&test_domain());
+
+        let mut pool = ReceiptPool::new(allocation_id).with_signing_scheme(scheme);
+        let borrows: Vec<Vec<u8>> = (0..4)
+            .map(|_| pool.commit(&test_signer(), U256::from(1)).unwrap())
+            .collect();
+
+        let verifier = ReceiptVerifier::new(allocation_id, allocation_signer);
+        assert_eq!(
+            verifier.verify(&borrows[0]),
+            Err(ReceiptError::InvalidSignature)
+        );
+        let verifier = verifier.with_signing_scheme(scheme);
+        assert!(verifier.verify(&borrows[0]).is_ok());
+        let receipt = BorrowedReceipt::try_from(borrows[0].as_slice()).unwrap();
+        assert_eq!(
+            receipt.recover_signer_with_scheme(&scheme),
+            Ok(test_signer_address())
+        );
+
+        let mut sorted: Vec<BorrowedReceipt> = borrows
+            .iter()
+            .map(|b| BorrowedReceipt::try_from(b.as_slice()).unwrap())
+            .collect();
+        sorted.sort_by_key(|r| *r.receipt_id());
+        let receipts: Vec<u8> = sorted
+            .iter()
+            .flat_map(|r| r.voucher_bytes().to_vec())
+            .collect();
+
+        assert_eq!(
+            receipts_to_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                &receipts
+            ),
+            Err(VoucherError::InvalidSignature(Some(BatchItem::Receipt {
+                index: 0,
+                receipt_id: *sorted[0].receipt_id(),
+            })))
+        );
+        let config = VoucherConfig {
+            signing_scheme: scheme,
+            ..Default::default()
+        };
+        let partial_vouchers: Vec<PartialVoucher> = receipts
+            .chunks(112 * 2)
+            .map(|receipts| {
+                config
+                    .receipts_to_partial_voucher(
+                        &allocation_id,
+                        &allocation_signer,
+                        &test_signer(),
+                        receipts,
+                    )
+                    .unwrap()
+            })
+            .collect();
+        let voucher = config
+            .combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers)
+            .unwrap();
+        assert_eq!(voucher.fees, U256::from(4));
+        assert_eq!(
+            voucher.verify_with_scheme(&scheme, &test_signer_address()),
+            Ok(())
+        );
+        assert_eq!(
+            voucher.verify(&test_signer_address()),
+            Err(VoucherError::InvalidSignature(None))
+        );
+        assert_eq!(
+            partial_vouchers[0].recover_signer_with_scheme(&scheme),
+            Ok(test_signer_address())
+        );
+    }
+}
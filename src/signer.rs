@@ -0,0 +1,149 @@
+use std::fmt;
+
+use secp256k1::{
+    ecdsa::{self, RecoverableSignature, RecoveryId},
+    Message, PublicKey, SecretKey,
+};
+
+use crate::prelude::*;
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum VerifyError {
+    MalformedSignature,
+    InvalidSignature,
+}
+
+impl std::error::Error for VerifyError {}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedSignature => write!(f, "Malformed signature"),
+            Self::InvalidSignature => write!(f, "Invalid signature"),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum SignError {
+    InvalidRecoveryId,
+    /// An external signer could not produce a signature. This has its
+    /// description of why.
+    SignerFailure(String),
+}
+
+impl std::error::Error for SignError {}
+
+impl fmt::Display for SignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRecoveryId => write!(f, "Invalid recovery ID"),
+            Self::SignerFailure(err) => write!(f, "Signer failure: {}", err),
+        }
+    }
+}
+
+/// Produces the signatures on receipts and vouchers. A `SecretKey` signs in
+/// process memory, but this can be implemented to keep the key in an HSM,
+/// KMS, or remote signing service instead.
+pub trait Signer {
+    /// Signs the message hash, as produced by a `SigningScheme`. The
+    /// signature ends with an Ethereum style recovery id (27 or 28).
+    fn sign_hash(&self, message: &Bytes32) -> Result<Signature, SignError>;
+
+    /// The key that partial vouchers signed by this signer are checked against.
+    fn verifying_key(&self) -> PublicKey;
+}
+
+impl Signer for SecretKey {
+    fn sign_hash(&self, message: &Bytes32) -> Result<Signature, SignError> {
+        let message = Message::from_digest_slice(message).unwrap();
+
+        let signature = SECP256K1.sign_ecdsa_recoverable(&message, self);
+        let (recovery_id, signature) = signature.serialize_compact();
+        let recovery_id = match recovery_id.to_i32() {
+            0 => 27,
+            1 => 28,
+            27 => 27,
+            28 => 28,
+            _ => return Err(SignError::InvalidRecoveryId),
+        };
+
+        let mut serialized = [0; 65];
+        serialized[..64].copy_from_slice(&signature);
+        serialized[64] = recovery_id;
+
+        Ok(serialized)
+    }
+
+    fn verifying_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&SECP256K1, self)
+    }
+}
+
+/// Something which signatures produced by this crate can be checked against.
+/// Verifying against a `PublicKey` is cheaper, but an `Address` is often all
+/// that is known about a signer on-chain.
+pub trait VerifyingKey: Sync {
+    fn verify_signature(&self, message: &Bytes32, signature: &Signature)
+        -> Result<(), VerifyError>;
+}
+
+impl VerifyingKey for PublicKey {
+    fn verify_signature(
+        &self,
+        message: &Bytes32,
+        signature: &Signature,
+    ) -> Result<(), VerifyError> {
+        let message = Message::from_digest_slice(message).unwrap();
+        let signature = ecdsa::Signature::from_compact(&signature[..64])
+            .map_err(|_| VerifyError::MalformedSignature)?;
+        SECP256K1
+            .verify_ecdsa(&message, &signature, self)
+            .map_err(|_| VerifyError::InvalidSignature)
+    }
+}
+
+impl VerifyingKey for Address {
+    fn verify_signature(
+        &self,
+        message: &Bytes32,
+        signature: &Signature,
+    ) -> Result<(), VerifyError> {
+        if &recover_address(message, signature)? != self {
+            return Err(VerifyError::InvalidSignature);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn recover(message: &Bytes32, signature: &Signature) -> Result<PublicKey, VerifyError> {
+    // Undo the Ethereum style recovery id written by `Signer::sign_hash`.
+    let recovery_id = match signature[64] {
+        0 | 27 => 0,
+        1 | 28 => 1,
+        _ => return Err(VerifyError::MalformedSignature),
+    };
+    let recovery_id =
+        RecoveryId::from_i32(recovery_id).map_err(|_| VerifyError::MalformedSignature)?;
+    let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id)
+        .map_err(|_| VerifyError::MalformedSignature)?;
+    let message = Message::from_digest_slice(message).unwrap();
+    SECP256K1
+        .recover_ecdsa(&message, &signature)
+        .map_err(|_| VerifyError::InvalidSignature)
+}
+
+/// Recovers the Ethereum address which signed the message.
+pub(crate) fn recover_address(
+    message: &Bytes32,
+    signature: &Signature,
+) -> Result<Address, VerifyError> {
+    Ok(public_key_address(&recover(message, signature)?))
+}
+
+/// The Ethereum address of a public key.
+pub fn public_key_address(key: &PublicKey) -> Address {
+    let hash = hash_bytes(&key.serialize_uncompressed()[1..]);
+    hash[12..].try_into().unwrap()
+}
@@ -0,0 +1,270 @@
+use std::{collections::HashSet, fmt, time::Instant};
+
+use crate::{pool::PooledReceipt, prelude::*, OutstandingReceipt, ReceiptPool, RetiredReceipt};
+
+// Snapshots are laid out as (with integers big-endian):
+// [magic, version, allocation_id, cached_count: u32, [receipt_id, unlocked_fee]*,
+//  outstanding_count: u32, [receipt_id, unlocked_fee, locked_fee]*,
+//  retired_count: u32, [receipt_id, unlocked_fee]*, checksum]
+// where the checksum is the keccak hash of everything before it. Version 1
+// snapshots are the same, but without the retired receipts.
+const MAGIC: &[u8; 4] = b"RCPT";
+const VERSION: u8 = 2;
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum SnapshotError {
+    InvalidLength,
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+    DuplicateReceiptId,
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength => write!(f, "Invalid snapshot length"),
+            Self::InvalidMagic => write!(f, "Not a receipt pool snapshot"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported snapshot version: {}", version)
+            }
+            Self::ChecksumMismatch => write!(f, "Snapshot checksum mismatch"),
+            Self::DuplicateReceiptId => write!(f, "Snapshot contains duplicate receipt ids"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<&'a [u8; N], SnapshotError> {
+        if self.data.len() < N {
+            return Err(SnapshotError::InvalidLength);
+        }
+        let (head, tail) = self.data.split_at(N);
+        self.data = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u256(&mut self) -> Result<U256, SnapshotError> {
+        Ok(U256::from_big_endian(self.take::<32>()?))
+    }
+
+    fn count(&mut self) -> Result<usize, SnapshotError> {
+        Ok(u32::from_be_bytes(*self.take()?) as usize)
+    }
+}
+
+impl ReceiptPool {
+    /// Serializes the pool so that it can be restored with `from_snapshot`,
+    /// e.g. across restarts.
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&self.allocation);
+        data.extend_from_slice(&(self.receipt_cache.len() as u32).to_be_bytes());
+        for receipt in &self.receipt_cache {
+            data.extend_from_slice(&receipt.receipt_id);
+            data.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
+        }
+        data.extend_from_slice(&(self.outstanding.len() as u32).to_be_bytes());
+        // Sorted so that snapshots of the same state are the same.
+        let mut outstanding: Vec<&OutstandingReceipt> = self.outstanding.values().collect();
+        outstanding.sort_by_key(|receipt| receipt.receipt_id);
+        for receipt in outstanding {
+            data.extend_from_slice(&receipt.receipt_id);
+            data.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
+            data.extend_from_slice(&to_be_bytes(receipt.locked_fee));
+        }
+        data.extend_from_slice(&(self.retired.len() as u32).to_be_bytes());
+        for receipt in &self.retired {
+            data.extend_from_slice(&receipt.receipt_id);
+            data.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
+        }
+        let checksum = hash_bytes(&data);
+        data.extend_from_slice(&checksum);
+        data
+    }
+
+    /// Restores a pool from `to_snapshot`. Commit times are not persisted, so
+    /// outstanding receipts are considered to have been committed at the
+    /// time they are restored. Neither are the signing scheme, selection
+    /// strategy, maximum unlocked fee, or RNG, which can be set again on the
+    /// restored pool.
+    pub fn from_snapshot(data: &[u8]) -> Result<Self, SnapshotError> {
+        if data.len() < MAGIC.len() + size_of::<u8>() + size_of::<Bytes32>() {
+            return Err(SnapshotError::InvalidLength);
+        }
+        let mut reader = Reader { data };
+        if reader.take()? != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let [version] = *reader.take()?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let (body, checksum) = data.split_at(data.len() - size_of::<Bytes32>());
+        if hash_bytes(body) != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let mut pool = ReceiptPool::new(*reader.take()?);
+        let mut ids = HashSet::new();
+        for _ in 0..reader.count()? {
+            let receipt_id = *reader.take()?;
+            if !ids.insert(receipt_id) {
+                return Err(SnapshotError::DuplicateReceiptId);
+            }
+            pool.receipt_cache.push_back(PooledReceipt {
+                unlocked_fee: reader.u256()?,
+                receipt_id,
+            });
+        }
+        let committed_at = Instant::now();
+        for _ in 0..reader.count()? {
+            let receipt_id = *reader.take()?;
+            if !ids.insert(receipt_id) {
+                return Err(SnapshotError::DuplicateReceiptId);
+            }
+            let receipt = OutstandingReceipt {
+                receipt_id,
+                unlocked_fee: reader.u256()?,
+                locked_fee: reader.u256()?,
+                committed_at,
+            };
+            pool.outstanding.insert(receipt_id, receipt);
+        }
+        if version >= 2 {
+            for _ in 0..reader.count()? {
+                let receipt_id = *reader.take()?;
+                if !ids.insert(receipt_id) {
+                    return Err(SnapshotError::DuplicateReceiptId);
+                }
+                pool.retired.push(RetiredReceipt {
+                    receipt_id,
+                    unlocked_fee: reader.u256()?,
+                });
+            }
+        }
+        if reader.data.len() != size_of::<Bytes32>() {
+            return Err(SnapshotError::InvalidLength);
+        }
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::*, QueryStatus};
+
+    fn test_pool() -> ReceiptPool {
+        let mut pool = ReceiptPool::new(bytes(5));
+        let borrows: Vec<Vec<u8>> = (1..=4)
+            .map(|fee| pool.commit(&test_signer(), U256::from(fee)).unwrap())
+            .collect();
+        pool.release(&borrows[0], QueryStatus::Success).unwrap();
+        pool.release(&borrows[1], QueryStatus::Failure).unwrap();
+        pool
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut pool = test_pool();
+        // Enough outstanding receipts that their order would vary.
+        for _ in 0..8 {
+            pool.commit(&test_signer(), U256::from(1)).unwrap();
+        }
+        let restored = ReceiptPool::from_snapshot(&pool.to_snapshot()).unwrap();
+
+        assert_eq!(restored.allocation, pool.allocation);
+        assert_eq!(restored.receipt_cache, pool.receipt_cache);
+        assert_eq!(restored.unlocked_fees(), pool.unlocked_fees());
+        assert_eq!(restored.locked_fees(), 15.into());
+        let mut ids: Vec<_> = pool.outstanding().map(|r| r.receipt_id).collect();
+        let mut restored_ids: Vec<_> = restored.outstanding().map(|r| r.receipt_id).collect();
+        ids.sort();
+        restored_ids.sort();
+        assert_eq!(ids, restored_ids);
+        assert_eq!(restored.to_snapshot(), pool.to_snapshot());
+    }
+
+    #[test]
+    fn restored_pool_continues_chains() {
+        let mut pool = test_pool();
+        let outstanding: Vec<Vec<u8>> = (0..2)
+            .map(|_| pool.commit(&test_signer(), U256::from(10)).unwrap())
+            .collect();
+        let mut restored = ReceiptPool::from_snapshot(&pool.to_snapshot()).unwrap();
+        for borrow in &outstanding {
+            restored.release(borrow, QueryStatus::Success).unwrap();
+        }
+        assert_eq!(restored.unlocked_fees(), 21.into());
+    }
+
+    #[test]
+    fn snapshot_retired_chains() {
+        let mut pool = test_pool().with_max_unlocked_fee(U256::zero());
+        let borrow = pool.commit(&test_signer(), U256::from(5)).unwrap();
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        assert_eq!(pool.retired().len(), 1);
+
+        let snapshot = pool.to_snapshot();
+        let restored = ReceiptPool::from_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.retired(), pool.retired());
+        assert_eq!(restored.unlocked_fees(), pool.unlocked_fees());
+
+        // Version 1 snapshots have no retired chains.
+        let pool = test_pool();
+        let snapshot = pool.to_snapshot();
+        let mut v1 = snapshot[..snapshot.len() - 36].to_vec();
+        v1[4] = 1;
+        v1.extend_from_slice(&hash_bytes(&v1));
+        let restored = ReceiptPool::from_snapshot(&v1).unwrap();
+        assert_eq!(restored.receipt_cache, pool.receipt_cache);
+        assert!(restored.retired().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_snapshots() {
+        let snapshot = test_pool().to_snapshot();
+
+        assert_eq!(
+            ReceiptPool::from_snapshot(&snapshot[..10]),
+            Err(SnapshotError::InvalidLength)
+        );
+
+        let mut bad_magic = snapshot.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(
+            ReceiptPool::from_snapshot(&bad_magic),
+            Err(SnapshotError::InvalidMagic)
+        );
+
+        let mut bad_version = snapshot.clone();
+        bad_version[4] = 3;
+        assert_eq!(
+            ReceiptPool::from_snapshot(&bad_version),
+            Err(SnapshotError::UnsupportedVersion(3))
+        );
+
+        let mut corrupt = snapshot.clone();
+        corrupt[40] ^= 1;
+        assert_eq!(
+            ReceiptPool::from_snapshot(&corrupt),
+            Err(SnapshotError::ChecksumMismatch)
+        );
+
+        let mut truncated = snapshot[..snapshot.len() - 33].to_vec();
+        truncated.extend_from_slice(&hash_bytes(&truncated));
+        assert_eq!(
+            ReceiptPool::from_snapshot(&truncated),
+            Err(SnapshotError::InvalidLength)
+        );
+    }
+}
@@ -1,8 +1,16 @@
-use std::{convert::TryFrom, time::Instant};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
 
+use rand::{rngs::StdRng, SeedableRng as _};
 use secp256k1::{PublicKey, SecretKey};
 
-use crate::{prelude::*, *};
+use crate::{
+    prelude::*,
+    voucher::{Receipt, FEE_RANGE, SIZE},
+    *,
+};
 
 pub fn bytes<const N: usize>(id: u8) -> [u8; N] {
     [id; N]
@@ -28,6 +36,11 @@ pub fn make_receipt() {
     debug_hex(&commit1);
 }
 
+/// Makes receipt ids, and so the order of receipts, reproducible.
+pub fn test_rng() -> StdRng {
+    StdRng::seed_from_u64(0x5eed)
+}
+
 pub fn test_signer() -> SecretKey {
     // Found this online. This is a test key with no funds.
     /*
@@ -40,6 +53,47 @@ pub fn test_signer() -> SecretKey {
         .unwrap()
 }
 
+pub fn test_signer_address() -> Address {
+    use rustc_hex::FromHex as _;
+    let address: Vec<u8> = "c61127cdfb5380df4214b0200b9a07c7c49d34f9"
+        .from_hex()
+        .unwrap();
+    address.try_into().unwrap()
+}
+
+/// Stands in for a signer outside of the process, such as an HSM, which can
+/// be made unavailable.
+pub struct ExternalSigner {
+    key: SecretKey,
+    available: AtomicBool,
+}
+
+impl ExternalSigner {
+    pub fn new(key: SecretKey) -> Self {
+        Self {
+            key,
+            available: AtomicBool::new(true),
+        }
+    }
+
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::SeqCst);
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn sign_hash(&self, message: &Bytes32) -> Result<Signature, SignError> {
+        if !self.available.load(Ordering::SeqCst) {
+            return Err(SignError::SignerFailure("signer unavailable".to_string()));
+        }
+        self.key.sign_hash(message)
+    }
+
+    fn verifying_key(&self) -> PublicKey {
+        self.key.verifying_key()
+    }
+}
+
 #[test]
 #[ignore = "Benchmark"]
 fn speed() {
@@ -55,7 +109,7 @@ fn speed() {
             borrows.push(commitment)
         }
         while let Some(borrow) = borrows.pop() {
-            pool.release(&borrow, QueryStatus::Success)
+            pool.release(&borrow, QueryStatus::Success).unwrap()
         }
     }
 
@@ -69,7 +123,7 @@ fn attempt_to_double_collect_with_partial_voucher_rejects() {
     let allocation_id = bytes(1);
 
     // Create a bunch of receipts
-    let mut pool = ReceiptPool::new(allocation_id);
+    let mut pool = ReceiptPool::new(allocation_id).with_rng(test_rng());
     let mut borrows = Vec::<Vec<u8>>::new();
     for _ in 0..10 {
         let fee = U256::from(1);
@@ -77,8 +131,8 @@ fn attempt_to_double_collect_with_partial_voucher_rejects() {
         borrows.push(commitment);
     }
 
-    let to_partial = |b| {
-        let receipts = receipts_from_borrows(b);
+    let to_partial = |b: &[Vec<u8>]| {
+        let receipts = sort_receipts(b).unwrap();
         receipts_to_partial_voucher(
             &allocation_id,
             &PublicKey::from_secret_key(&SECP256K1, &test_signer()),
@@ -88,8 +142,8 @@ fn attempt_to_double_collect_with_partial_voucher_rejects() {
         .unwrap()
     };
 
-    let partial_1 = to_partial(borrows[5..].to_vec());
-    let partial_2 = to_partial(borrows[..5].to_vec());
+    let partial_1 = to_partial(&borrows[5..]);
+    let partial_2 = to_partial(&borrows[..5]);
 
     for ordering in [
         vec![partial_1.clone(), partial_2.clone()],
@@ -97,10 +151,140 @@ fn attempt_to_double_collect_with_partial_voucher_rejects() {
         vec![partial_1.clone(), partial_1.clone()],
     ] {
         let err = combine_partial_vouchers(&allocation_id, &test_signer(), &ordering);
-        assert_eq!(err, Err(VoucherError::UnorderedPartialVouchers));
+        assert_eq!(
+            err,
+            Err(VoucherError::UnorderedPartialVouchers(
+                BatchItem::PartialVoucher { index: 1 }
+            ))
+        );
     }
 }
 
+#[test]
+fn merge_partial_vouchers_as_a_tree() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 16);
+
+    let mut level: Vec<PartialVoucher> = receipts
+        .chunks(112 * 2)
+        .map(|receipts| {
+            receipts_to_partial_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                receipts,
+            )
+            .unwrap()
+        })
+        .collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| merge_partial_vouchers(&allocation_id, &test_signer(), pair).unwrap())
+            .collect();
+    }
+
+    // Warning: This is relying on an ECDSA implementation compatible with RFC 6979
+    // (deterministic usage of signatures).
+    let expected = receipts_to_partial_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(level[0], expected);
+    assert_eq!(
+        combine_partial_vouchers(&allocation_id, &test_signer(), &level),
+        receipts_to_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts
+        )
+    );
+
+    assert_eq!(
+        merge_partial_vouchers(
+            &allocation_id,
+            &test_signer(),
+            &[expected.clone(), expected]
+        ),
+        Err(VoucherError::UnorderedPartialVouchers(
+            BatchItem::PartialVoucher { index: 1 }
+        ))
+    );
+    assert_eq!(
+        merge_partial_vouchers(&allocation_id, &test_signer(), &[]),
+        Err(VoucherError::NoValue)
+    );
+}
+
+#[test]
+fn combine_partial_vouchers_after_rotating_signer() {
+    let allocation_id = bytes(1);
+    let receipts = create_receipts(allocation_id, 4);
+    let new_signer = SecretKey::from_slice(&bytes::<32>(7)).unwrap();
+    let new_signer_address =
+        public_key_address(&PublicKey::from_secret_key(&SECP256K1, &new_signer));
+
+    // One partial voucher from before the rotation, and one after
+    let partial_vouchers = [
+        receipts_to_partial_voucher(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &receipts[..112 * 2],
+        )
+        .unwrap(),
+        receipts_to_partial_voucher(
+            &allocation_id,
+            &test_signer_address(),
+            &new_signer,
+            &receipts[112 * 2..],
+        )
+        .unwrap(),
+    ];
+
+    let trusted = [test_signer_address(), new_signer_address];
+    let voucher = combine_partial_vouchers_with_signers(
+        &allocation_id,
+        &trusted,
+        &new_signer,
+        &partial_vouchers,
+    )
+    .unwrap();
+    assert_eq!(voucher.fees, U256::from(4));
+    assert_eq!(voucher.recover_signer(), Ok(new_signer_address));
+    let merged = merge_partial_vouchers_with_signers(
+        &allocation_id,
+        &trusted,
+        &new_signer,
+        &partial_vouchers,
+    )
+    .unwrap();
+    assert_eq!(merged.recover_signer(), Ok(new_signer_address));
+
+    assert_eq!(
+        combine_partial_vouchers(&allocation_id, &new_signer, &partial_vouchers),
+        Err(VoucherError::InvalidSignature(Some(
+            BatchItem::PartialVoucher { index: 0 }
+        )))
+    );
+    assert_eq!(
+        combine_partial_vouchers_with_signers(
+            &allocation_id,
+            &[] as &[Address],
+            &new_signer,
+            &partial_vouchers
+        ),
+        Err(VoucherError::InvalidSignature(Some(
+            BatchItem::PartialVoucher { index: 0 }
+        )))
+    );
+}
+
 #[test]
 fn vouchers() {
     let allocation_id = bytes(1);
@@ -111,7 +295,7 @@ fn vouchers() {
     let mut fees = U256::zero();
     for i in 2..10 {
         for borrow in borrows.drain(..) {
-            pool.release(&borrow, QueryStatus::Success);
+            pool.release(&borrow, QueryStatus::Success).unwrap();
         }
         for _ in 0..i {
             let fee = U256::from(1);
@@ -120,7 +304,7 @@ fn vouchers() {
             borrows.push(commitment);
         }
     }
-    let receipts = receipts_from_borrows(borrows);
+    let receipts = sort_receipts(&borrows).unwrap();
 
     // Convert to voucher
     let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
@@ -197,7 +381,7 @@ fn partial_vouchers_combine() {
             .unwrap()
     };
 
-    let mut rng = rand::thread_rng();
+    let mut rng = test_rng();
     let receipt_count = rng.gen_range(2..=1000);
     let receipts = create_receipts(allocation_id, receipt_count);
 
@@ -224,23 +408,612 @@ fn partial_vouchers_combine() {
     assert_eq!(oneshot_receipt, combined_voucher);
 }
 
-fn create_receipts(allocation_id: Address, count: usize) -> Vec<u8> {
+#[test]
+fn vouchers_verify() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let voucher_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let other_signer = SecretKey::from_slice(&bytes::<32>(7)).unwrap();
+
+    let receipts = create_receipts(allocation_id, 10);
+    let partial_voucher = receipts_to_partial_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    let voucher = combine_partial_vouchers(
+        &allocation_id,
+        &test_signer(),
+        std::slice::from_ref(&partial_voucher),
+    )
+    .unwrap();
+
+    assert_eq!(voucher.verify(&voucher_signer), Ok(()));
+    assert_eq!(voucher.verify(&test_signer_address()), Ok(()));
+    assert_eq!(partial_voucher.verify(&voucher_signer), Ok(()));
+    assert_eq!(partial_voucher.verify(&test_signer_address()), Ok(()));
+
+    let other_signer = PublicKey::from_secret_key(&SECP256K1, &other_signer);
+    assert_eq!(
+        voucher.verify(&other_signer),
+        Err(VoucherError::InvalidSignature(None))
+    );
+    assert_eq!(
+        partial_voucher.verify(&bytes::<20>(7)),
+        Err(VoucherError::InvalidSignature(None))
+    );
+
+    let mut tampered = voucher.clone();
+    tampered.fees += U256::one();
+    assert_eq!(
+        tampered.verify(&voucher_signer),
+        Err(VoucherError::InvalidSignature(None))
+    );
+    assert_eq!(
+        tampered.verify(&test_signer_address()),
+        Err(VoucherError::InvalidSignature(None))
+    );
+    let mut tampered = partial_voucher.clone();
+    tampered.receipt_id_max = bytes(0xff);
+    assert_eq!(
+        tampered.verify(&voucher_signer),
+        Err(VoucherError::InvalidSignature(None))
+    );
+}
+
+#[test]
+fn recover_signer_addresses() {
+    let allocation_id = bytes(1);
+    let receipts = create_receipts(allocation_id, 10);
+
+    assert_eq!(
+        public_key_address(&PublicKey::from_secret_key(&SECP256K1, &test_signer())),
+        test_signer_address()
+    );
+
+    // Vouchers can be created knowing only the allocation signer address.
+    let partial_voucher = receipts_to_partial_voucher(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(partial_voucher.recover_signer(), Ok(test_signer_address()));
+    assert_eq!(voucher.recover_signer(), Ok(test_signer_address()));
+
+    assert_eq!(
+        receipts_to_voucher(&allocation_id, &bytes::<20>(2), &test_signer(), &receipts),
+        Err(VoucherError::InvalidSignature(Some(
+            Receipt::new(&receipts).batch_item(0)
+        )))
+    );
+}
+
+#[test]
+fn vouchers_binary_round_trip() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 3);
+    let partial_voucher = receipts_to_partial_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    let voucher = partial_voucher.voucher.clone();
+
+    let bytes = voucher.to_bytes();
+    assert_eq!(&bytes[..20], &allocation_id);
+    assert_eq!(Voucher::from_bytes(&bytes), Ok(voucher));
+    assert_eq!(
+        Voucher::from_bytes(&bytes[1..]),
+        Err(VoucherError::InvalidData(None))
+    );
+
+    let bytes = partial_voucher.to_bytes();
+    assert_eq!(PartialVoucher::from_bytes(&bytes), Ok(partial_voucher));
+    assert_eq!(
+        PartialVoucher::from_bytes(&bytes[..VOUCHER_LEN]),
+        Err(VoucherError::InvalidData(None))
+    );
+}
+
+#[test]
+fn voucher_value_overflow_is_reported() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+
+    // Two receipts on separate chains which together exceed U256::MAX
     let mut pool = ReceiptPool::new(allocation_id);
+    let half = U256::MAX / 2 + 1;
+    let borrows = vec![
+        pool.commit(&test_signer(), half).unwrap(),
+        pool.commit(&test_signer(), half).unwrap(),
+    ];
+    let receipts = sort_receipts(&borrows).unwrap();
+    assert_eq!(
+        receipts_to_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts
+        ),
+        Err(VoucherError::VoucherValueTooLarge(
+            Receipt::new(&receipts[SIZE..]).batch_item(1)
+        ))
+    );
+
+    let partial_vouchers: Vec<PartialVoucher> = receipts
+        .chunks(112)
+        .map(|receipts| {
+            receipts_to_partial_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                receipts,
+            )
+            .unwrap()
+        })
+        .collect();
+    assert_eq!(
+        combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers),
+        Err(VoucherError::VoucherValueTooLarge(
+            BatchItem::PartialVoucher { index: 1 }
+        ))
+    );
+}
+
+#[test]
+fn voucher_max_value_is_configurable() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 10);
+    let config = VoucherConfig {
+        max_value: U256::from(5),
+        ..Default::default()
+    };
+
+    let err = config
+        .receipts_to_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts,
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        VoucherError::VoucherValueTooLarge(BatchItem::Receipt { index: 5, .. })
+    ));
+
+    let partial_vouchers: Vec<PartialVoucher> = receipts
+        .chunks(112 * 3)
+        .map(|receipts| {
+            config
+                .receipts_to_partial_voucher(
+                    &allocation_id,
+                    &allocation_signer,
+                    &test_signer(),
+                    receipts,
+                )
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(
+        config.combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers),
+        Err(VoucherError::VoucherValueTooLarge(
+            BatchItem::PartialVoucher { index: 1 }
+        ))
+    );
+
+    let config = VoucherConfig {
+        max_value: U256::from(10),
+        ..Default::default()
+    };
+    let voucher = config
+        .receipts_to_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts,
+        )
+        .unwrap();
+    assert_eq!(voucher.fees, U256::from(10));
+}
+
+#[test]
+fn partition_receipts_rejects_individually() {
+    let allocation_id = bytes(1);
+    let receipts = create_receipts(allocation_id, 5);
+    let receipt = |i: usize| &receipts[SIZE * i..SIZE * (i + 1)];
+    let rejected = |index, i: usize, reason| ExcludedReceipt {
+        item: Receipt::new(receipt(i)).batch_item(index),
+        fees: U256::from(1),
+        reason,
+    };
+
+    // A tampered receipt, then a duplicate
+    let mut tampered = receipt(1).to_vec();
+    tampered[FEE_RANGE.end - 1] += 1;
+    let data = [
+        receipt(0),
+        &tampered,
+        receipt(2),
+        receipt(2),
+        receipt(3),
+        receipt(4),
+    ]
+    .concat();
+
+    let config = VoucherConfig {
+        max_value: U256::from(3),
+        ..Default::default()
+    };
+    let partition = config
+        .partition_receipts(&allocation_id, &test_signer_address(), &data)
+        .unwrap();
+    assert_eq!(
+        partition.valid,
+        [receipt(0), receipt(2), receipt(3)].concat()
+    );
+    assert_eq!(
+        partition.rejected,
+        vec![
+            ExcludedReceipt {
+                fees: U256::from(2),
+                ..rejected(1, 1, ExclusionReason::InvalidSignature)
+            },
+            rejected(3, 2, ExclusionReason::Duplicate),
+            rejected(5, 4, ExclusionReason::ValueTooLarge),
+        ]
+    );
+
+    // The valid subset always makes a voucher
+    let voucher = config
+        .receipts_to_voucher(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &partition.valid,
+        )
+        .unwrap();
+    assert_eq!(voucher.fees, U256::from(3));
+
+    assert_eq!(
+        partition_receipts(&allocation_id, &test_signer_address(), &data[1..]),
+        Err(VoucherError::InvalidData(None))
+    );
+}
+
+#[test]
+fn lenient_vouchers_exclude_bad_receipts() {
+    let allocation_id = bytes(1);
+    let receipts = create_receipts(allocation_id, 5);
+    let receipt = |i: usize| &receipts[SIZE * i..SIZE * (i + 1)];
+    let excluded = |index, i: usize, fees: u64, reason| ExcludedReceipt {
+        item: Receipt::new(receipt(i)).batch_item(index),
+        fees: U256::from(fees),
+        reason,
+    };
+
+    let mut tampered = receipt(1).to_vec();
+    tampered[FEE_RANGE.end - 1] += 1;
+    let zero_fee = ReceiptPool::new(allocation_id)
+        .commit(&test_signer(), U256::zero())
+        .unwrap();
+    let zero_fee = BorrowedReceipt::try_from(zero_fee.as_slice())
+        .unwrap()
+        .voucher_bytes();
+    let data = [
+        receipt(0),
+        &tampered,
+        receipt(2),
+        receipt(2),
+        zero_fee,
+        receipt(3),
+        receipt(0),
+        receipt(4),
+    ]
+    .concat();
+
+    let config = VoucherConfig {
+        max_value: U256::from(3),
+        ..Default::default()
+    };
+    let (partial_voucher, report) = config
+        .receipts_to_partial_voucher_lenient(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &data,
+        )
+        .unwrap();
+    assert_eq!(partial_voucher.voucher.fees, U256::from(3));
+    assert_eq!(&partial_voucher.receipt_id_min, Receipt::new(receipt(0)).id);
+    assert_eq!(&partial_voucher.receipt_id_max, Receipt::new(receipt(3)).id);
+    assert_eq!(
+        report.excluded[..2],
+        [
+            excluded(1, 1, 2, ExclusionReason::InvalidSignature),
+            excluded(3, 2, 1, ExclusionReason::Duplicate),
+        ]
+    );
+    assert_eq!(report.excluded[2].reason, ExclusionReason::ZeroFee);
+    assert_eq!(
+        report.excluded[3..],
+        [
+            excluded(6, 0, 1, ExclusionReason::Unordered),
+            excluded(7, 4, 1, ExclusionReason::ValueTooLarge),
+        ]
+    );
+    assert_eq!(report.value_lost, U256::from(4));
+
+    // Nothing is excluded from good data, and the voucher is the same as
+    // the strict one.
+    let (voucher, report) = receipts_to_voucher_lenient(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(report, ExclusionReport::default());
+    assert_eq!(
+        Ok(voucher),
+        receipts_to_voucher(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &receipts
+        )
+    );
+
+    assert_eq!(
+        receipts_to_voucher_lenient(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            zero_fee
+        ),
+        Err(VoucherError::NoValue)
+    );
+
+    // Of two states of one chain, the later is kept wherever it comes.
+    let mut pool = ReceiptPool::new(allocation_id);
+    let first = pool.commit(&test_signer(), U256::from(1)).unwrap();
+    pool.release(&first, QueryStatus::Success).unwrap();
+    let second = pool.commit(&test_signer(), U256::from(2)).unwrap();
+    let receipt_id = *BorrowedReceipt::try_from(first.as_slice())
+        .unwrap()
+        .receipt_id();
+    let states: [&[u8]; 2] = [&first, &second].map(|b| {
+        BorrowedReceipt::try_from(b.as_slice())
+            .unwrap()
+            .voucher_bytes()
+    });
+    for (data, excluded_index) in [(states.concat(), 0), ([states[1], states[0]].concat(), 1)] {
+        let (voucher, report) = receipts_to_voucher_lenient(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &data,
+        )
+        .unwrap();
+        assert_eq!(voucher.fees, U256::from(3));
+        assert_eq!(report.value_lost, U256::zero());
+        let duplicate = report.excluded[0];
+        assert_eq!(duplicate.reason, ExclusionReason::Duplicate);
+        assert_eq!(duplicate.fees, U256::from(1));
+        assert_eq!(
+            duplicate.item,
+            BatchItem::Receipt {
+                index: excluded_index,
+                receipt_id
+            }
+        );
+        // The same verdicts as partitioning
+        let partition = partition_receipts(&allocation_id, &test_signer_address(), &data).unwrap();
+        assert_eq!(partition.valid, states[1]);
+        assert_eq!(partition.rejected, report.excluded);
+    }
+}
+
+#[test]
+fn sort_receipts_in_any_order() {
+    let allocation_id = bytes(1);
+    let mut pool = ReceiptPool::new(allocation_id);
+
+    // Three states of one chain, and two other chains
+    let first = pool.commit(&test_signer(), U256::from(1)).unwrap();
+    pool.release(&first, QueryStatus::Success).unwrap();
+    let second = pool.commit(&test_signer(), U256::from(2)).unwrap();
+    pool.release(&second, QueryStatus::Success).unwrap();
+    let third = pool.commit(&test_signer(), U256::from(4)).unwrap();
+    let others = [
+        pool.commit(&test_signer(), U256::from(8)).unwrap(),
+        pool.commit(&test_signer(), U256::from(16)).unwrap(),
+    ];
+
+    // Mixed formats, out of order
+    fn voucher_bytes(borrow: &[u8]) -> &[u8] {
+        BorrowedReceipt::try_from(borrow).unwrap().voucher_bytes()
+    }
+    let receipts: Vec<&[u8]> = vec![
+        voucher_bytes(&others[1]),
+        &second,
+        voucher_bytes(&third),
+        &others[0],
+        &first,
+        &third,
+    ];
+    let sorted = sort_receipts(&receipts).unwrap();
+    assert_eq!(sorted.len(), 112 * 3);
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &sorted,
+    )
+    .unwrap();
+    assert_eq!(voucher.fees, U256::from(7 + 8 + 16));
+
+    assert_eq!(
+        sort_receipts(&[&first[1..]]),
+        Err(VoucherError::InvalidData(None))
+    );
+}
+
+#[test]
+fn collapse_keeps_latest_valid_state() {
+    let allocation_id = bytes(1);
+    let mut pool = ReceiptPool::new(allocation_id);
+
+    // Three states of one chain, and another chain
+    let first = pool.commit(&test_signer(), U256::from(1)).unwrap();
+    pool.release(&first, QueryStatus::Success).unwrap();
+    let second = pool.commit(&test_signer(), U256::from(2)).unwrap();
+    pool.release(&second, QueryStatus::Success).unwrap();
+    let third = pool.commit(&test_signer(), U256::from(4)).unwrap();
+    let other = pool.commit(&test_signer(), U256::from(8)).unwrap();
+    let third = BorrowedReceipt::try_from(third.as_slice()).unwrap();
+
+    // The third state with its fee raised, and so a bad signature
+    let forged = [
+        &to_be_bytes(U256::from(100))[..],
+        third.receipt_id(),
+        third.signature(),
+    ]
+    .concat();
+
+    let receipts: Vec<&[u8]> = vec![
+        &forged,
+        &first,
+        third.voucher_bytes(),
+        &other,
+        &second,
+        third.voucher_bytes(),
+    ];
+    let collapsed = collapse_receipts(&allocation_id, &test_signer_address(), &receipts).unwrap();
+    assert_eq!(collapsed.superseded, 2);
+    assert_eq!(collapsed.duplicates, 1);
+    assert_eq!(
+        collapsed.rejected,
+        vec![ExcludedReceipt {
+            item: BatchItem::Receipt {
+                index: 0,
+                receipt_id: *third.receipt_id(),
+            },
+            fees: U256::from(100),
+            reason: ExclusionReason::InvalidSignature,
+        }]
+    );
+    assert_eq!(collapsed.receipts.len(), 112 * 2);
+
+    // The forged state doesn't cost the chain its value in either mode.
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &collapsed.receipts,
+    )
+    .unwrap();
+    assert_eq!(voucher.fees, U256::from(7 + 8));
+    let (lenient, report) = receipts_to_voucher_lenient(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &collapsed.receipts,
+    )
+    .unwrap();
+    assert_eq!(lenient, voucher);
+    assert!(report.excluded.is_empty());
+
+    // Whereas sorting alone keeps the forged state.
+    let sorted = sort_receipts(&receipts).unwrap();
+    assert!(receipts_to_voucher(
+        &allocation_id,
+        &test_signer_address(),
+        &test_signer(),
+        &sorted
+    )
+    .is_err());
+}
+
+#[test]
+fn external_signer() {
+    let allocation_id = bytes(1);
+    let signer = ExternalSigner::new(test_signer());
+    let failure = || "signer unavailable".to_string();
+
+    let mut pool = ReceiptPool::new(allocation_id);
+    let borrows: Vec<Vec<u8>> = (0..4)
+        .map(|_| pool.commit(&signer, U256::from(1)).unwrap())
+        .collect();
+    let receipts = sort_receipts(&borrows).unwrap();
+    let partial_voucher =
+        receipts_to_partial_voucher(&allocation_id, &test_signer_address(), &signer, &receipts)
+            .unwrap();
+    let voucher = combine_partial_vouchers(
+        &allocation_id,
+        &signer,
+        std::slice::from_ref(&partial_voucher),
+    )
+    .unwrap();
+    // Warning: This is relying on an ECDSA implementation compatible with RFC 6979
+    // (deterministic usage of signatures).
+    assert_eq!(
+        Ok(voucher),
+        receipts_to_voucher(
+            &allocation_id,
+            &test_signer_address(),
+            &test_signer(),
+            &receipts
+        )
+    );
+
+    // Failures are reported, and the receipt isn't left outstanding.
+    signer.set_available(false);
+    assert_eq!(
+        pool.commit(&signer, U256::from(1)),
+        Err(BorrowFail::SignerFailure(failure()))
+    );
+    assert_eq!(pool.outstanding().count(), 4);
+    let pool = ConcurrentReceiptPool::from(pool);
+    assert_eq!(
+        pool.commit(&signer, U256::from(1)),
+        Err(BorrowFail::SignerFailure(failure()))
+    );
+    assert_eq!(
+        receipts_to_voucher(&allocation_id, &test_signer_address(), &signer, &receipts),
+        Err(VoucherError::SignerFailure(failure()))
+    );
+    assert_eq!(
+        combine_partial_vouchers(
+            &allocation_id,
+            &signer,
+            std::slice::from_ref(&partial_voucher)
+        ),
+        Err(VoucherError::SignerFailure(failure()))
+    );
+}
+
+pub fn create_receipts(allocation_id: Address, count: usize) -> Vec<u8> {
+    let mut pool = ReceiptPool::new(allocation_id).with_rng(test_rng());
     let mut borrows = Vec::<Vec<u8>>::new();
     for _ in 1..=count {
         let commitment = pool.commit(&test_signer(), U256::from(1)).unwrap();
         borrows.push(commitment);
     }
-    receipts_from_borrows(borrows)
-}
-
-fn receipts_from_borrows(mut borrows: Vec<Vec<u8>>) -> Vec<u8> {
-    let mut receipts = Vec::with_capacity(112 * borrows.len());
-    // Sort by receipt id
-    borrows.sort_by_key(|b| ReceiptId::try_from(&b[52..67]).unwrap());
-    // Serialize
-    for borrow in borrows {
-        receipts.extend_from_slice(&borrow[20..132]);
-    }
-    receipts
+    sort_receipts(&borrows).unwrap()
 }
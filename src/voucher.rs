@@ -1,21 +1,47 @@
-use std::fmt;
+use std::{cmp::Ordering, fmt};
 
 use itertools::Itertools as _;
-use secp256k1::{ecdsa, Message, PublicKey, SecretKey};
-use tiny_keccak::{Hasher, Keccak};
 
-use crate::prelude::*;
+use crate::{prelude::*, signer::recover_address, BorrowedReceipt, BORROWED_RECEIPT_LEN};
 
 #[derive(Debug, PartialEq)]
 pub enum VoucherError {
-    InvalidData,
-    InvalidSignature,
+    /// Carries the item with the malformed signature, if the error was in a
+    /// batch rather than in its framing.
+    InvalidData(Option<BatchItem>),
+    /// Carries the item with the bad signature, if there was a batch.
+    InvalidSignature(Option<BatchItem>),
     JsonDeserialization(String),
-    UnorderedReceipts,
-    UnorderedPartialVouchers,
+    /// The first item whose receipt id is not above the previous.
+    UnorderedReceipts(BatchItem),
+    UnorderedPartialVouchers(BatchItem),
     NoValue,
     InvalidRecoveryId,
-    VoucherValueTooLarge,
+    SignerFailure(String),
+    VoucherValueTooLarge(BatchItem),
+}
+
+/// Identifies the item in a batch passed to the voucher functions which
+/// caused an error.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BatchItem {
+    Receipt { index: usize, receipt_id: ReceiptId },
+    PartialVoucher { index: usize },
+}
+
+impl fmt::Display for BatchItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Receipt { index, receipt_id } => {
+                write!(f, "receipt {} (id 0x", index)?;
+                for byte in receipt_id {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, ")")
+            }
+            Self::PartialVoucher { index } => write!(f, "partial voucher {}", index),
+        }
+    }
 }
 
 impl std::error::Error for VoucherError {}
@@ -23,14 +49,48 @@ impl std::error::Error for VoucherError {}
 impl fmt::Display for VoucherError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::InvalidData => write!(f, "Invalid receipts data"),
-            Self::InvalidSignature => write!(f, "Receipts are not signed for the given allocation"),
+            Self::InvalidData(None) => write!(f, "Invalid receipts data"),
+            Self::InvalidData(Some(item)) => write!(f, "Invalid data at {}", item),
+            Self::InvalidSignature(None) => {
+                write!(f, "Receipts are not signed for the given allocation")
+            }
+            Self::InvalidSignature(Some(item)) => {
+                write!(f, "{} is not signed for the given allocation", item)
+            }
             Self::JsonDeserialization(err) => write!(f, "JSON error: {}", err),
-            Self::UnorderedReceipts => write!(f, "Unordered receipts"),
-            Self::UnorderedPartialVouchers => write!(f, "Unordered partial vouchers"),
+            Self::UnorderedReceipts(item) => write!(f, "Unordered receipts at {}", item),
+            Self::UnorderedPartialVouchers(item) => {
+                write!(f, "Unordered partial vouchers at {}", item)
+            }
             Self::NoValue => write!(f, "Receipts have no value"),
             Self::InvalidRecoveryId => SignError::InvalidRecoveryId.fmt(f),
-            Self::VoucherValueTooLarge => write!(f, "Voucher value too large"),
+            Self::SignerFailure(err) => write!(f, "Signer failure: {}", err),
+            Self::VoucherValueTooLarge(item) => write!(f, "Voucher value too large at {}", item),
+        }
+    }
+}
+
+impl VoucherError {
+    /// The item in the batch which caused the error, if any.
+    pub fn batch_item(&self) -> Option<BatchItem> {
+        match self {
+            Self::InvalidData(item) | Self::InvalidSignature(item) => *item,
+            Self::UnorderedReceipts(item)
+            | Self::UnorderedPartialVouchers(item)
+            | Self::VoucherValueTooLarge(item) => Some(*item),
+            Self::JsonDeserialization(_)
+            | Self::NoValue
+            | Self::InvalidRecoveryId
+            | Self::SignerFailure(_) => None,
+        }
+    }
+
+    /// Attributes a signature error to an item in the batch.
+    pub(crate) fn at(self, item: BatchItem) -> Self {
+        match self {
+            Self::InvalidData(None) => Self::InvalidData(Some(item)),
+            Self::InvalidSignature(None) => Self::InvalidSignature(Some(item)),
+            err => err,
         }
     }
 }
@@ -39,35 +99,63 @@ impl From<SignError> for VoucherError {
     fn from(err: SignError) -> Self {
         match err {
             SignError::InvalidRecoveryId => Self::InvalidRecoveryId,
+            SignError::SignerFailure(err) => Self::SignerFailure(err),
+        }
+    }
+}
+
+impl From<VerifyError> for VoucherError {
+    fn from(err: VerifyError) -> Self {
+        match err {
+            VerifyError::MalformedSignature => Self::InvalidData(None),
+            VerifyError::InvalidSignature => Self::InvalidSignature(None),
         }
     }
 }
 
-const FEE_RANGE: Range = next_range::<U256>(0..0);
+pub(crate) const FEE_RANGE: Range = next_range::<U256>(0..0);
 const RECEIPT_ID_RANGE: Range = next_range::<ReceiptId>(FEE_RANGE);
 const SIGNATURE_RANGE: Range = next_range::<Signature>(RECEIPT_ID_RANGE);
-const SIZE: usize = SIGNATURE_RANGE.end; // 112 bytes, last I checked.
+pub(crate) const SIZE: usize = SIGNATURE_RANGE.end; // 112 bytes, last I checked.
+
+// Layout of the binary encoding of vouchers. A partial voucher is a voucher
+// followed by its receipt id bounds.
+const VOUCHER_ALLOCATION_ID_RANGE: Range = next_range::<Address>(0..0);
+const VOUCHER_FEES_RANGE: Range = next_range::<U256>(VOUCHER_ALLOCATION_ID_RANGE);
+const VOUCHER_SIGNATURE_RANGE: Range = next_range::<Signature>(VOUCHER_FEES_RANGE);
+pub const VOUCHER_LEN: usize = VOUCHER_SIGNATURE_RANGE.end;
+const RECEIPT_ID_MIN_RANGE: Range = next_range::<ReceiptId>(VOUCHER_SIGNATURE_RANGE);
+const RECEIPT_ID_MAX_RANGE: Range = next_range::<ReceiptId>(RECEIPT_ID_MIN_RANGE);
+pub const PARTIAL_VOUCHER_LEN: usize = RECEIPT_ID_MAX_RANGE.end;
 
 struct Receipts<'r> {
     pub data: &'r [u8],
     pub index: usize,
 }
 
-struct Receipt<'r> {
+pub(crate) struct Receipt<'r> {
     pub fees: U256,
     pub id: &'r ReceiptId,
     pub signature: &'r Signature,
 }
 
 impl Receipts<'_> {
-    fn new(data: &[u8]) -> Result<Receipts, VoucherError> {
-        if data.len() % SIZE != 0 {
-            return Err(VoucherError::InvalidData);
+    fn new(data: &[u8]) -> Result<Receipts<'_>, VoucherError> {
+        if !data.len().is_multiple_of(SIZE) {
+            return Err(VoucherError::InvalidData(None));
         }
         Ok(Receipts { data, index: 0 })
     }
 }
 
+/// The lowest and highest receipt ids, of receipts which have been verified
+/// and so are sorted and not empty.
+pub(crate) fn receipt_id_bounds(data: &[u8]) -> Result<(ReceiptId, ReceiptId), VoucherError> {
+    let receipt_id_min = *Receipts::new(data)?.next().unwrap().id;
+    let receipt_id_max = *Receipts::new(data)?.last().unwrap().id;
+    Ok((receipt_id_min, receipt_id_max))
+}
+
 impl<'r> Iterator for Receipts<'r> {
     type Item = Receipt<'r>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -76,11 +164,24 @@ impl<'r> Iterator for Receipts<'r> {
         }
         let chunk = &self.data[(self.index * SIZE)..];
         self.index += 1;
-        Some(Receipt {
+        Some(Receipt::new(chunk))
+    }
+}
+
+impl<'r> Receipt<'r> {
+    pub(crate) fn new(chunk: &'r [u8]) -> Self {
+        Receipt {
             fees: U256::from_big_endian(&chunk[FEE_RANGE]),
             id: (&chunk[RECEIPT_ID_RANGE]).try_into().unwrap(),
             signature: (&chunk[SIGNATURE_RANGE]).try_into().unwrap(),
-        })
+        }
+    }
+
+    pub(crate) fn batch_item(&self, index: usize) -> BatchItem {
+        BatchItem::Receipt {
+            index,
+            receipt_id: *self.id,
+        }
     }
 }
 
@@ -91,168 +192,925 @@ pub struct Voucher {
     pub signature: Signature,
 }
 
-#[derive(Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PartialVoucher {
     pub voucher: Voucher,
     pub receipt_id_min: ReceiptId,
     pub receipt_id_max: ReceiptId,
 }
 
+impl Voucher {
+    pub fn to_bytes(&self) -> [u8; VOUCHER_LEN] {
+        let mut bytes = [0; VOUCHER_LEN];
+        bytes[VOUCHER_ALLOCATION_ID_RANGE].copy_from_slice(&self.allocation_id);
+        bytes[VOUCHER_FEES_RANGE].copy_from_slice(&to_be_bytes(self.fees));
+        bytes[VOUCHER_SIGNATURE_RANGE].copy_from_slice(&self.signature);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VoucherError> {
+        if bytes.len() != VOUCHER_LEN {
+            return Err(VoucherError::InvalidData(None));
+        }
+        Ok(Self {
+            allocation_id: bytes[VOUCHER_ALLOCATION_ID_RANGE].try_into().unwrap(),
+            fees: U256::from_big_endian(&bytes[VOUCHER_FEES_RANGE]),
+            signature: bytes[VOUCHER_SIGNATURE_RANGE].try_into().unwrap(),
+        })
+    }
+
+    /// Recovers the address of the voucher signer.
+    pub fn recover_signer(&self) -> Result<Address, VoucherError> {
+        self.recover_signer_with_scheme(&SigningScheme::Legacy)
+    }
+
+    pub fn recover_signer_with_scheme(
+        &self,
+        signing_scheme: &SigningScheme,
+    ) -> Result<Address, VoucherError> {
+        Ok(recover_address(
+            &self.message(signing_scheme),
+            &self.signature,
+        )?)
+    }
+
+    /// Checks that the voucher was signed by the voucher signer.
+    pub fn verify(&self, voucher_signer: &impl VerifyingKey) -> Result<(), VoucherError> {
+        self.verify_with_scheme(&SigningScheme::Legacy, voucher_signer)
+    }
+
+    pub fn verify_with_scheme(
+        &self,
+        signing_scheme: &SigningScheme,
+        voucher_signer: &impl VerifyingKey,
+    ) -> Result<(), VoucherError> {
+        voucher_signer.verify_signature(&self.message(signing_scheme), &self.signature)?;
+        Ok(())
+    }
+
+    fn message(&self, signing_scheme: &SigningScheme) -> Bytes32 {
+        signing_scheme.voucher_hash(&self.allocation_id, self.fees)
+    }
+}
+
+impl PartialVoucher {
+    pub fn to_bytes(&self) -> [u8; PARTIAL_VOUCHER_LEN] {
+        let mut bytes = [0; PARTIAL_VOUCHER_LEN];
+        bytes[..VOUCHER_LEN].copy_from_slice(&self.voucher.to_bytes());
+        bytes[RECEIPT_ID_MIN_RANGE].copy_from_slice(&self.receipt_id_min);
+        bytes[RECEIPT_ID_MAX_RANGE].copy_from_slice(&self.receipt_id_max);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VoucherError> {
+        if bytes.len() != PARTIAL_VOUCHER_LEN {
+            return Err(VoucherError::InvalidData(None));
+        }
+        Ok(Self {
+            voucher: Voucher::from_bytes(&bytes[..VOUCHER_LEN])?,
+            receipt_id_min: bytes[RECEIPT_ID_MIN_RANGE].try_into().unwrap(),
+            receipt_id_max: bytes[RECEIPT_ID_MAX_RANGE].try_into().unwrap(),
+        })
+    }
+
+    /// Recovers the address of the voucher signer.
+    pub fn recover_signer(&self) -> Result<Address, VoucherError> {
+        self.recover_signer_with_scheme(&SigningScheme::Legacy)
+    }
+
+    pub fn recover_signer_with_scheme(
+        &self,
+        signing_scheme: &SigningScheme,
+    ) -> Result<Address, VoucherError> {
+        Ok(recover_address(
+            &self.message(signing_scheme),
+            &self.voucher.signature,
+        )?)
+    }
+
+    /// Checks that the partial voucher was signed by the voucher signer.
+    pub fn verify(&self, voucher_signer: &impl VerifyingKey) -> Result<(), VoucherError> {
+        self.verify_with_scheme(&SigningScheme::Legacy, voucher_signer)
+    }
+
+    pub fn verify_with_scheme(
+        &self,
+        signing_scheme: &SigningScheme,
+        voucher_signer: &impl VerifyingKey,
+    ) -> Result<(), VoucherError> {
+        voucher_signer.verify_signature(&self.message(signing_scheme), &self.voucher.signature)?;
+        Ok(())
+    }
+
+    fn message(&self, signing_scheme: &SigningScheme) -> Bytes32 {
+        signing_scheme.partial_voucher_hash(
+            &self.voucher.allocation_id,
+            self.voucher.fees,
+            &self.receipt_id_min,
+            &self.receipt_id_max,
+        )
+    }
+}
+
+/// Settings for creating vouchers. The free functions of the same names use
+/// the defaults.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VoucherConfig {
+    /// The largest voucher value that will be produced. This should match
+    /// what the contract redeeming the vouchers accepts.
+    pub max_value: U256,
+    /// Applies to receipts, partial vouchers, and vouchers alike.
+    pub signing_scheme: SigningScheme,
+}
+
+impl Default for VoucherConfig {
+    fn default() -> Self {
+        Self {
+            max_value: U256::MAX,
+            signing_scheme: SigningScheme::Legacy,
+        }
+    }
+}
+
+/// Receipts split by `partition_receipts` into those which can go into a
+/// voucher and those which can't.
+#[derive(Debug, PartialEq)]
+pub struct ReceiptPartition {
+    /// The accepted receipts, in the format taken by `receipts_to_voucher`.
+    pub valid: Vec<u8>,
+    /// Why each of the other receipts was rejected.
+    pub rejected: Vec<ExcludedReceipt>,
+}
+
+/// What the lenient voucher functions left out, which are the receipts
+/// rejected by `partition_receipts`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ExclusionReport {
+    pub excluded: Vec<ExcludedReceipt>,
+    /// The sum of the fees on the excluded receipts, other than duplicates
+    /// whose value is collected through the receipt they duplicate.
+    pub value_lost: U256,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ExcludedReceipt {
+    /// Indexed by position in the original data.
+    pub item: BatchItem,
+    pub fees: U256,
+    pub reason: ExclusionReason,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExclusionReason {
+    ZeroFee,
+    MalformedSignature,
+    InvalidSignature,
+    /// An earlier state of a chain, or a copy of its latest state, which is
+    /// next to the receipt kept for the chain.
+    Duplicate,
+    /// Has a lower receipt id than the last receipt kept.
+    Unordered,
+    /// Would take the voucher value past the configured maximum.
+    ValueTooLarge,
+}
+
+impl ExclusionReason {
+    fn from_signature_error(err: &VoucherError) -> Self {
+        match err {
+            VoucherError::InvalidData(_) => Self::MalformedSignature,
+            _ => Self::InvalidSignature,
+        }
+    }
+}
+
+// The receipts kept by the lenient functions.
+struct Filtered {
+    fees: U256,
+    receipt_id_min: ReceiptId,
+    receipt_id_max: ReceiptId,
+    report: ExclusionReport,
+}
+
 /// Security: The voucher_signer must be dedicated to this purpose, hold no funds,
 /// and sign no other messages except with this method. Similarly, the allocation
 /// signer must only sign allocations and serve no other purpose and hold no funds.
 /// One exception is that they may be the same signer. They are allowed to be different
 /// in case we want to rotate the voucher_signer and keep old receipts intact. Having
 /// them be the same signer is ok only because they sign messages of different lengths.
-
 pub fn receipts_to_voucher(
     allocation_id: &Address,
-    allocation_signer: &PublicKey,
-    voucher_signer: &SecretKey,
+    allocation_signer: &impl VerifyingKey,
+    voucher_signer: &impl Signer,
     data: &[u8],
 ) -> Result<Voucher, VoucherError> {
-    let fees = verify_receipts(allocation_id, allocation_signer, data)?;
-    let mut message = Vec::new();
-    message.extend_from_slice(allocation_id);
-    message.extend_from_slice(&to_be_bytes(fees));
-    Ok(Voucher {
-        allocation_id: *allocation_id,
-        fees,
-        signature: sign(&message, voucher_signer)?,
-    })
+    VoucherConfig::default().receipts_to_voucher(
+        allocation_id,
+        allocation_signer,
+        voucher_signer,
+        data,
+    )
 }
 
 pub fn receipts_to_partial_voucher(
     allocation_id: &Address,
-    allocation_signer: &PublicKey,
-    voucher_signer: &SecretKey,
+    allocation_signer: &impl VerifyingKey,
+    voucher_signer: &impl Signer,
     data: &[u8],
 ) -> Result<PartialVoucher, VoucherError> {
-    let fees = verify_receipts(allocation_id, allocation_signer, data)?;
-    let receipt_id_min = *Receipts::new(data)?.next().unwrap().id;
-    let receipt_id_max = *Receipts::new(data)?.last().unwrap().id;
-    let mut message = Vec::new();
-    message.extend_from_slice(allocation_id);
-    message.extend_from_slice(&to_be_bytes(fees));
-    message.extend_from_slice(&receipt_id_min);
-    message.extend_from_slice(&receipt_id_max);
-    Ok(PartialVoucher {
-        voucher: Voucher {
-            allocation_id: *allocation_id,
-            fees,
-            signature: sign(&message, voucher_signer)?,
-        },
-        receipt_id_min,
-        receipt_id_max,
-    })
+    VoucherConfig::default().receipts_to_partial_voucher(
+        allocation_id,
+        allocation_signer,
+        voucher_signer,
+        data,
+    )
 }
 
-fn verify_receipts(
+pub fn partition_receipts(
     allocation_id: &Address,
-    allocation_signer: &PublicKey,
+    allocation_signer: &impl VerifyingKey,
     data: &[u8],
-) -> Result<U256, VoucherError> {
-    // Verify the receipts are sorted and ascending.
-    // This also verifies their uniqueness.
-    if Receipts::new(data)?
-        .map(|receipt| *receipt.id)
-        .tuple_windows()
-        .any(|(a, b)| a >= b)
-    {
-        return Err(VoucherError::UnorderedReceipts);
-    }
-
-    // Verify signatures
-    for receipt in Receipts::new(data)? {
-        // Create the signed message from the receipt data.
-        // Allocationid is "untrusted" and kept separate from the receipt data.
-        // This also de-duplicates it in the message.
-        let mut hasher = Keccak::v256();
-        hasher.update(allocation_id);
-        hasher.update(&to_be_bytes(receipt.fees));
-        hasher.update(receipt.id);
-        let mut message = Bytes32::default();
-        hasher.finalize(&mut message);
+) -> Result<ReceiptPartition, VoucherError> {
+    VoucherConfig::default().partition_receipts(allocation_id, allocation_signer, data)
+}
 
-        let message = Message::from_digest_slice(&message).unwrap();
-        let signature = ecdsa::Signature::from_compact(&receipt.signature[..64])
-            .map_err(|_| VoucherError::InvalidData)?;
-        SECP256K1
-            .verify_ecdsa(&message, &signature, allocation_signer)
-            .map_err(|_| VoucherError::InvalidSignature)?;
-    }
+/// Like `receipts_to_voucher`, but leaves out bad receipts instead of failing.
+pub fn receipts_to_voucher_lenient(
+    allocation_id: &Address,
+    allocation_signer: &impl VerifyingKey,
+    voucher_signer: &impl Signer,
+    data: &[u8],
+) -> Result<(Voucher, ExclusionReport), VoucherError> {
+    VoucherConfig::default().receipts_to_voucher_lenient(
+        allocation_id,
+        allocation_signer,
+        voucher_signer,
+        data,
+    )
+}
 
-    let fees = Receipts::new(data)?
-        .map(|receipt| receipt.fees)
-        .fold(U256::zero(), |sum, fees| sum.saturating_add(fees));
-    // The contract will revert if this is 0
-    if fees == U256::zero() {
-        return Err(VoucherError::NoValue);
-    }
-    Ok(fees)
+pub fn receipts_to_partial_voucher_lenient(
+    allocation_id: &Address,
+    allocation_signer: &impl VerifyingKey,
+    voucher_signer: &impl Signer,
+    data: &[u8],
+) -> Result<(PartialVoucher, ExclusionReport), VoucherError> {
+    VoucherConfig::default().receipts_to_partial_voucher_lenient(
+        allocation_id,
+        allocation_signer,
+        voucher_signer,
+        data,
+    )
+}
+
+pub fn merge_partial_vouchers(
+    allocation_id: &Address,
+    voucher_signer: &impl Signer,
+    partial_vouchers: &[PartialVoucher],
+) -> Result<PartialVoucher, VoucherError> {
+    VoucherConfig::default().merge_partial_vouchers(allocation_id, voucher_signer, partial_vouchers)
+}
+
+pub fn combine_partial_vouchers_with_signers(
+    allocation_id: &Address,
+    partial_voucher_signers: &[impl VerifyingKey],
+    voucher_signer: &impl Signer,
+    partial_vouchers: &[PartialVoucher],
+) -> Result<Voucher, VoucherError> {
+    VoucherConfig::default().combine_partial_vouchers_with_signers(
+        allocation_id,
+        partial_voucher_signers,
+        voucher_signer,
+        partial_vouchers,
+    )
+}
+
+pub fn merge_partial_vouchers_with_signers(
+    allocation_id: &Address,
+    partial_voucher_signers: &[impl VerifyingKey],
+    voucher_signer: &impl Signer,
+    partial_vouchers: &[PartialVoucher],
+) -> Result<PartialVoucher, VoucherError> {
+    VoucherConfig::default().merge_partial_vouchers_with_signers(
+        allocation_id,
+        partial_voucher_signers,
+        voucher_signer,
+        partial_vouchers,
+    )
+}
+
+/// Prepares receipts collected in any order for `receipts_to_voucher`. Each
+/// may be either a full receipt from `ReceiptPool::commit` or already in the
+/// 112 byte voucher format. Of the receipts with the same id, the one with the
+/// highest fee is kept as the latest state of the chain. Signatures aren't
+/// checked here, so for receipts which may be corrupt or forged, use
+/// `collapse_receipts` instead.
+pub fn sort_receipts(receipts: &[impl AsRef<[u8]>]) -> Result<Vec<u8>, VoucherError> {
+    let mut stripped = strip_receipts(receipts)?;
+    // Highest fee first within each id, so that dedup keeps it.
+    stripped.sort_by(|a, b| by_id_then_highest_fee(a, b));
+    stripped.dedup_by_key(|receipt| *Receipt::new(receipt).id);
+    Ok(stripped.concat())
+}
+
+pub fn collapse_receipts(
+    allocation_id: &Address,
+    allocation_signer: &impl VerifyingKey,
+    receipts: &[impl AsRef<[u8]>],
+) -> Result<CollapsedReceipts, VoucherError> {
+    VoucherConfig::default().collapse_receipts(allocation_id, allocation_signer, receipts)
+}
+
+/// The outcome of `collapse_receipts`.
+#[derive(Debug, PartialEq)]
+pub struct CollapsedReceipts {
+    /// Sorted by receipt id, in the 112 byte voucher format.
+    pub receipts: Vec<u8>,
+    /// How many receipts were dropped for a later state of the same chain.
+    pub superseded: usize,
+    /// How many receipts were exact copies of a kept receipt.
+    pub duplicates: usize,
+    /// Receipts which failed verification, indexed by their position in the
+    /// input.
+    pub rejected: Vec<ExcludedReceipt>,
+}
+
+fn strip_receipts(receipts: &[impl AsRef<[u8]>]) -> Result<Vec<&[u8]>, VoucherError> {
+    receipts
+        .iter()
+        .map(|receipt| {
+            let receipt = receipt.as_ref();
+            match receipt.len() {
+                SIZE => Ok(receipt),
+                BORROWED_RECEIPT_LEN => {
+                    Ok(BorrowedReceipt::try_from(receipt).unwrap().voucher_bytes())
+                }
+                _ => Err(VoucherError::InvalidData(None)),
+            }
+        })
+        .collect()
+}
+
+fn by_id_then_highest_fee(a: &[u8], b: &[u8]) -> Ordering {
+    let (a, b) = (Receipt::new(a), Receipt::new(b));
+    a.id.cmp(b.id).then(b.fees.cmp(&a.fees))
 }
 
 pub fn combine_partial_vouchers(
     allocation_id: &Address,
-    voucher_signer: &SecretKey,
+    voucher_signer: &impl Signer,
     partial_vouchers: &[PartialVoucher],
 ) -> Result<Voucher, VoucherError> {
-    if partial_vouchers.is_empty() {
-        return Err(VoucherError::NoValue);
+    VoucherConfig::default().combine_partial_vouchers(
+        allocation_id,
+        voucher_signer,
+        partial_vouchers,
+    )
+}
+
+impl VoucherConfig {
+    pub fn receipts_to_voucher(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        voucher_signer: &impl Signer,
+        data: &[u8],
+    ) -> Result<Voucher, VoucherError> {
+        let fees = self.verify_receipts(allocation_id, allocation_signer, data)?;
+        self.sign_voucher(allocation_id, fees, voucher_signer)
     }
 
-    // All partial voucher ID range bounds are ordered.
-    if !partial_vouchers
-        .iter()
-        .all(|pv| pv.receipt_id_min <= pv.receipt_id_max)
-    {
-        return Err(VoucherError::UnorderedPartialVouchers);
+    pub fn receipts_to_partial_voucher(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        voucher_signer: &impl Signer,
+        data: &[u8],
+    ) -> Result<PartialVoucher, VoucherError> {
+        let fees = self.verify_receipts(allocation_id, allocation_signer, data)?;
+        let (receipt_id_min, receipt_id_max) = receipt_id_bounds(data)?;
+        self.sign_partial_voucher(
+            allocation_id,
+            fees,
+            receipt_id_min,
+            receipt_id_max,
+            voucher_signer,
+        )
+    }
+
+    /// A receipt id is reused for every commitment on its chain, each with the
+    /// fee accumulated so far. So of all the observed receipts with the same
+    /// id, only the one with the highest fee that passes verification is kept,
+    /// as the latest state of the chain. A corrupt or forged receipt therefore
+    /// can't displace a valid one. This takes receipts in the same formats as
+    /// `sort_receipts`.
+    pub fn collapse_receipts(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        receipts: &[impl AsRef<[u8]>],
+    ) -> Result<CollapsedReceipts, VoucherError> {
+        let stripped = strip_receipts(receipts)?;
+        let mut order: Vec<usize> = (0..stripped.len()).collect();
+        // Highest fee first within each id, so the latest state is tried first.
+        order.sort_by(|&a, &b| by_id_then_highest_fee(stripped[a], stripped[b]));
+
+        let mut collapsed = CollapsedReceipts {
+            receipts: Vec::new(),
+            superseded: 0,
+            duplicates: 0,
+            rejected: Vec::new(),
+        };
+        let mut kept: Option<&[u8]> = None;
+        for index in order {
+            let chunk = stripped[index];
+            let receipt = Receipt::new(chunk);
+            match kept {
+                Some(kept) if kept == chunk => collapsed.duplicates += 1,
+                Some(kept) if Receipt::new(kept).id == receipt.id => collapsed.superseded += 1,
+                _ => match self.verify_signature(allocation_id, allocation_signer, &receipt) {
+                    Ok(()) => {
+                        collapsed.receipts.extend_from_slice(chunk);
+                        kept = Some(chunk);
+                    }
+                    Err(err) => collapsed.rejected.push(ExcludedReceipt {
+                        item: receipt.batch_item(index),
+                        fees: receipt.fees,
+                        reason: ExclusionReason::from_signature_error(&err),
+                    }),
+                },
+            }
+        }
+        Ok(collapsed)
+    }
+
+    /// Checks each receipt rather than failing the whole batch on the first
+    /// bad one. Only malformed data as a whole is an error. Receipts with zero
+    /// fees or bad signatures are rejected, as are any out of order relative
+    /// to the previously accepted receipt, or which would take the total past
+    /// `max_value`. Of adjacent receipts with the same id, the one with the
+    /// highest fee is kept as the latest state of the chain.
+    pub fn partition_receipts(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        data: &[u8],
+    ) -> Result<ReceiptPartition, VoucherError> {
+        let (partition, _) = self.classify_receipts(allocation_id, allocation_signer, data)?;
+        Ok(partition)
+    }
+
+    /// Creates a voucher from the receipts accepted by `partition_receipts`.
+    /// Only malformed data as a whole, or having nothing left, is an error.
+    pub fn receipts_to_voucher_lenient(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        voucher_signer: &impl Signer,
+        data: &[u8],
+    ) -> Result<(Voucher, ExclusionReport), VoucherError> {
+        let filtered = self.filter_receipts(allocation_id, allocation_signer, data)?;
+        let voucher = self.sign_voucher(allocation_id, filtered.fees, voucher_signer)?;
+        Ok((voucher, filtered.report))
+    }
+
+    pub fn receipts_to_partial_voucher_lenient(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        voucher_signer: &impl Signer,
+        data: &[u8],
+    ) -> Result<(PartialVoucher, ExclusionReport), VoucherError> {
+        let filtered = self.filter_receipts(allocation_id, allocation_signer, data)?;
+        let partial_voucher = self.sign_partial_voucher(
+            allocation_id,
+            filtered.fees,
+            filtered.receipt_id_min,
+            filtered.receipt_id_max,
+            voucher_signer,
+        )?;
+        Ok((partial_voucher, filtered.report))
+    }
+
+    fn filter_receipts(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        data: &[u8],
+    ) -> Result<Filtered, VoucherError> {
+        let (partition, fees) = self.classify_receipts(allocation_id, allocation_signer, data)?;
+        let mut kept = Receipts::new(&partition.valid)?;
+        // The contract will revert if this is 0
+        let receipt_id_min = *kept.next().ok_or(VoucherError::NoValue)?.id;
+        let receipt_id_max = kept.last().map_or(receipt_id_min, |receipt| *receipt.id);
+        let value_lost = partition
+            .rejected
+            .iter()
+            .filter(|excluded| excluded.reason != ExclusionReason::Duplicate)
+            .fold(U256::zero(), |sum, excluded| {
+                sum.saturating_add(excluded.fees)
+            });
+        Ok(Filtered {
+            fees,
+            receipt_id_min,
+            receipt_id_max,
+            report: ExclusionReport {
+                excluded: partition.rejected,
+                value_lost,
+            },
+        })
+    }
+
+    /// The rules shared by `partition_receipts` and the lenient functions.
+    /// Also returns the sum of the fees of the accepted receipts.
+    fn classify_receipts(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        data: &[u8],
+    ) -> Result<(ReceiptPartition, U256), VoucherError> {
+        let mut partition = ReceiptPartition {
+            valid: Vec::new(),
+            rejected: Vec::new(),
+        };
+        let mut fees = U256::zero();
+        // The index, fees, and id of the last accepted receipt.
+        let mut last: Option<(usize, U256, &ReceiptId)> = None;
+        for (index, (receipt, chunk)) in Receipts::new(data)?.zip(data.chunks(SIZE)).enumerate() {
+            let reason = if receipt.fees.is_zero() {
+                ExclusionReason::ZeroFee
+            } else if let Err(err) =
+                self.verify_signature(allocation_id, allocation_signer, &receipt)
+            {
+                ExclusionReason::from_signature_error(&err)
+            } else if let Some((last_index, last_fees, _)) =
+                last.filter(|(_, _, id)| *id == receipt.id)
+            {
+                // A later state of the same chain replaces the accepted one.
+                match self.add_fees(fees - last_fees, receipt.fees) {
+                    _ if receipt.fees <= last_fees => ExclusionReason::Duplicate,
+                    None => ExclusionReason::ValueTooLarge,
+                    Some(sum) => {
+                        fees = sum;
+                        let start = partition.valid.len() - SIZE;
+                        partition.valid[start..].copy_from_slice(chunk);
+                        last = Some((index, receipt.fees, receipt.id));
+                        partition.rejected.push(ExcludedReceipt {
+                            item: receipt.batch_item(last_index),
+                            fees: last_fees,
+                            reason: ExclusionReason::Duplicate,
+                        });
+                        continue;
+                    }
+                }
+            } else if matches!(last, Some((_, _, last_id)) if last_id > receipt.id) {
+                ExclusionReason::Unordered
+            } else if let Some(sum) = self.add_fees(fees, receipt.fees) {
+                fees = sum;
+                last = Some((index, receipt.fees, receipt.id));
+                partition.valid.extend_from_slice(chunk);
+                continue;
+            } else {
+                ExclusionReason::ValueTooLarge
+            };
+            partition.rejected.push(ExcludedReceipt {
+                item: receipt.batch_item(index),
+                fees: receipt.fees,
+                reason,
+            });
+        }
+        Ok((partition, fees))
+    }
+
+    pub(crate) fn sign_voucher(
+        &self,
+        allocation_id: &Address,
+        fees: U256,
+        voucher_signer: &impl Signer,
+    ) -> Result<Voucher, VoucherError> {
+        let message = self.signing_scheme.voucher_hash(allocation_id, fees);
+        Ok(Voucher {
+            allocation_id: *allocation_id,
+            fees,
+            signature: voucher_signer.sign_hash(&message)?,
+        })
+    }
+
+    pub(crate) fn sign_partial_voucher(
+        &self,
+        allocation_id: &Address,
+        fees: U256,
+        receipt_id_min: ReceiptId,
+        receipt_id_max: ReceiptId,
+        voucher_signer: &impl Signer,
+    ) -> Result<PartialVoucher, VoucherError> {
+        let message = self.signing_scheme.partial_voucher_hash(
+            allocation_id,
+            fees,
+            &receipt_id_min,
+            &receipt_id_max,
+        );
+        Ok(PartialVoucher {
+            voucher: Voucher {
+                allocation_id: *allocation_id,
+                fees,
+                signature: voucher_signer.sign_hash(&message)?,
+            },
+            receipt_id_min,
+            receipt_id_max,
+        })
+    }
+
+    pub(crate) fn verify_receipts(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        data: &[u8],
+    ) -> Result<U256, VoucherError> {
+        // Verify the receipts are sorted and ascending.
+        // This also verifies their uniqueness.
+        if let Some((index, (_, receipt_id))) = Receipts::new(data)?
+            .map(|receipt| *receipt.id)
+            .tuple_windows()
+            .enumerate()
+            .find(|(_, (a, b))| a >= b)
+        {
+            return Err(VoucherError::UnorderedReceipts(BatchItem::Receipt {
+                index: index + 1,
+                receipt_id,
+            }));
+        }
+
+        // Verify signatures
+        #[cfg(not(feature = "parallel"))]
+        self.verify_signatures(allocation_id, allocation_signer, data)?;
+        #[cfg(feature = "parallel")]
+        self.verify_signatures_parallel(allocation_id, allocation_signer, data)?;
+
+        let mut fees = U256::zero();
+        for (index, receipt) in Receipts::new(data)?.enumerate() {
+            fees = self
+                .add_fees(fees, receipt.fees)
+                .ok_or(VoucherError::VoucherValueTooLarge(
+                    receipt.batch_item(index),
+                ))?;
+        }
+        // The contract will revert if this is 0
+        if fees == U256::zero() {
+            return Err(VoucherError::NoValue);
+        }
+        Ok(fees)
+    }
+
+    pub(crate) fn verify_signature(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        receipt: &Receipt,
+    ) -> Result<(), VoucherError> {
+        // Create the signed message from the receipt data.
+        // Allocationid is "untrusted" and kept separate from the receipt data.
+        // This also de-duplicates it in the message.
+        let message = self
+            .signing_scheme
+            .receipt_hash(allocation_id, receipt.fees, receipt.id);
+
+        allocation_signer.verify_signature(&message, receipt.signature)?;
+        Ok(())
+    }
+
+    fn verify_signatures(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        data: &[u8],
+    ) -> Result<(), VoucherError> {
+        for (index, receipt) in Receipts::new(data)?.enumerate() {
+            self.verify_signature(allocation_id, allocation_signer, &receipt)
+                .map_err(|err| err.at(receipt.batch_item(index)))?;
+        }
+        Ok(())
+    }
+
+    /// Spreads signature verification across threads. The error returned is
+    /// for the first invalid receipt, the same as `verify_signatures`.
+    #[cfg(feature = "parallel")]
+    fn verify_signatures_parallel(
+        &self,
+        allocation_id: &Address,
+        allocation_signer: &impl VerifyingKey,
+        data: &[u8],
+    ) -> Result<(), VoucherError> {
+        use rayon::prelude::*;
+
+        // Not worth the overhead of spreading small batches across threads.
+        const MIN_PARALLEL_RECEIPTS: usize = 64;
+        if data.len() < SIZE * MIN_PARALLEL_RECEIPTS {
+            return self.verify_signatures(allocation_id, allocation_signer, data);
+        }
+        Receipts::new(data)?;
+
+        match data
+            .par_chunks(SIZE)
+            .enumerate()
+            .find_map_first(|(index, chunk)| {
+                let receipt = Receipt::new(chunk);
+                self.verify_signature(allocation_id, allocation_signer, &receipt)
+                    .err()
+                    .map(|err| err.at(receipt.batch_item(index)))
+            }) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    pub fn combine_partial_vouchers(
+        &self,
+        allocation_id: &Address,
+        voucher_signer: &impl Signer,
+        partial_vouchers: &[PartialVoucher],
+    ) -> Result<Voucher, VoucherError> {
+        let partial_voucher_signer = voucher_signer.verifying_key();
+        self.combine_partial_vouchers_with_signers(
+            allocation_id,
+            &[partial_voucher_signer],
+            voucher_signer,
+            partial_vouchers,
+        )
+    }
+
+    /// Like `combine_partial_vouchers`, but accepts partial vouchers signed by
+    /// any of the trusted signers rather than only the voucher signer. This
+    /// allows the voucher signer to be rotated while partial vouchers from the
+    /// old one are still outstanding.
+    pub fn combine_partial_vouchers_with_signers(
+        &self,
+        allocation_id: &Address,
+        partial_voucher_signers: &[impl VerifyingKey],
+        voucher_signer: &impl Signer,
+        partial_vouchers: &[PartialVoucher],
+    ) -> Result<Voucher, VoucherError> {
+        let fees =
+            self.verify_partial_vouchers(allocation_id, partial_voucher_signers, partial_vouchers)?;
+
+        // Create signature for complete voucher
+        self.sign_voucher(allocation_id, fees, voucher_signer)
+    }
+
+    /// Merges partial vouchers into one covering all of their receipt id
+    /// ranges, which can itself be merged or combined into a voucher later.
+    pub fn merge_partial_vouchers(
+        &self,
+        allocation_id: &Address,
+        voucher_signer: &impl Signer,
+        partial_vouchers: &[PartialVoucher],
+    ) -> Result<PartialVoucher, VoucherError> {
+        let partial_voucher_signer = voucher_signer.verifying_key();
+        self.merge_partial_vouchers_with_signers(
+            allocation_id,
+            &[partial_voucher_signer],
+            voucher_signer,
+            partial_vouchers,
+        )
+    }
+
+    pub fn merge_partial_vouchers_with_signers(
+        &self,
+        allocation_id: &Address,
+        partial_voucher_signers: &[impl VerifyingKey],
+        voucher_signer: &impl Signer,
+        partial_vouchers: &[PartialVoucher],
+    ) -> Result<PartialVoucher, VoucherError> {
+        let fees =
+            self.verify_partial_vouchers(allocation_id, partial_voucher_signers, partial_vouchers)?;
+
+        // The ranges are ordered, so the first and last bound the union.
+        self.sign_partial_voucher(
+            allocation_id,
+            fees,
+            partial_vouchers[0].receipt_id_min,
+            partial_vouchers[partial_vouchers.len() - 1].receipt_id_max,
+            voucher_signer,
+        )
+    }
+
+    pub(crate) fn verify_partial_vouchers(
+        &self,
+        allocation_id: &Address,
+        partial_voucher_signers: &[impl VerifyingKey],
+        partial_vouchers: &[PartialVoucher],
+    ) -> Result<U256, VoucherError> {
+        if partial_vouchers.is_empty() {
+            return Err(VoucherError::NoValue);
+        }
+
+        // All partial voucher ID range bounds are ordered.
+        if let Some(index) = partial_vouchers
+            .iter()
+            .position(|pv| pv.receipt_id_min > pv.receipt_id_max)
+        {
+            return Err(VoucherError::UnorderedPartialVouchers(
+                BatchItem::PartialVoucher { index },
+            ));
+        }
+        // All partial voucher ID ranges are non-overlapping.
+        if let Some(index) = partial_vouchers
+            .iter()
+            .tuple_windows()
+            .position(|(a, b)| a.receipt_id_max >= b.receipt_id_min)
+        {
+            return Err(VoucherError::UnorderedPartialVouchers(
+                BatchItem::PartialVoucher { index: index + 1 },
+            ));
+        }
+
+        // Verify signatures
+        for (index, partial_voucher) in partial_vouchers.iter().enumerate() {
+            let item = BatchItem::PartialVoucher { index };
+            if &partial_voucher.voucher.allocation_id != allocation_id {
+                return Err(VoucherError::InvalidSignature(Some(item)));
+            }
+            // Any one of the signers will do.
+            let mut verified = Err(VoucherError::InvalidSignature(None));
+            for signer in partial_voucher_signers {
+                verified = partial_voucher.verify_with_scheme(&self.signing_scheme, signer);
+                if verified.is_ok() {
+                    break;
+                }
+            }
+            verified.map_err(|err| err.at(item))?;
+        }
+
+        let mut fees = U256::zero();
+        for (index, partial_voucher) in partial_vouchers.iter().enumerate() {
+            fees = self.add_fees(fees, partial_voucher.voucher.fees).ok_or(
+                VoucherError::VoucherValueTooLarge(BatchItem::PartialVoucher { index }),
+            )?;
+        }
+        if fees == U256::zero() {
+            return Err(VoucherError::NoValue);
+        }
+        Ok(fees)
+    }
+
+    /// Adds to a running voucher value, unless that would overflow or exceed
+    /// the maximum.
+    pub(crate) fn add_fees(&self, sum: U256, fees: U256) -> Option<U256> {
+        sum.checked_add(fees).filter(|sum| sum <= &self.max_value)
+    }
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use secp256k1::PublicKey;
+
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn parallel_verification_matches_serial() {
+        let allocation_id = bytes(1);
+        let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+        let config = VoucherConfig::default();
+        let receipts = create_receipts(allocation_id, 200);
+
+        let verify = |data: &[u8]| {
+            let serial = config.verify_signatures(&allocation_id, &allocation_signer, data);
+            let parallel =
+                config.verify_signatures_parallel(&allocation_id, &allocation_signer, data);
+            assert_eq!(serial, parallel);
+            serial
+        };
+
+        assert_eq!(verify(&receipts), Ok(()));
+        assert_eq!(
+            verify(&receipts[..111]),
+            Err(VoucherError::InvalidData(None))
+        );
+        assert_eq!(
+            verify(&receipts[..receipts.len() - 1]),
+            Err(VoucherError::InvalidData(None))
+        );
+
+        // An invalid signature late in the batch and a malformed one early on.
+        // Both paths must report the first.
+        let mut corrupt = receipts.clone();
+        corrupt[SIZE * 150 + FEE_RANGE.end - 1] ^= 1;
+        let item = |index| Some(Receipt::new(&receipts[SIZE * index..]).batch_item(index));
+        assert_eq!(
+            verify(&corrupt),
+            Err(VoucherError::InvalidSignature(item(150)))
+        );
+        corrupt[SIZE * 20 + SIGNATURE_RANGE.start..][..64].fill(0xff);
+        assert_eq!(verify(&corrupt), Err(VoucherError::InvalidData(item(20))));
+
+        assert_eq!(
+            config.receipts_to_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                &corrupt
+            ),
+            Err(VoucherError::InvalidData(item(20)))
+        );
     }
-    // All partial voucher ID ranges are non-overlapping.
-    if !partial_vouchers
-        .iter()
-        .tuple_windows()
-        .all(|(a, b)| a.receipt_id_max < b.receipt_id_min)
-    {
-        return Err(VoucherError::UnorderedPartialVouchers);
-    }
-
-    // Verify signatures
-    let partial_voucher_signer = PublicKey::from_secret_key(&SECP256K1, voucher_signer);
-    for partial_voucher in partial_vouchers {
-        let mut hasher = Keccak::v256();
-        hasher.update(allocation_id);
-        hasher.update(&to_be_bytes(partial_voucher.voucher.fees));
-        hasher.update(&partial_voucher.receipt_id_min);
-        hasher.update(&partial_voucher.receipt_id_max);
-        let mut message = Bytes32::default();
-        hasher.finalize(&mut message);
-
-        let message = Message::from_digest_slice(&message).unwrap();
-        let signature = ecdsa::Signature::from_compact(&partial_voucher.voucher.signature[..64])
-            .map_err(|_| VoucherError::InvalidData)?;
-        SECP256K1
-            .verify_ecdsa(&message, &signature, &partial_voucher_signer)
-            .map_err(|_| VoucherError::InvalidSignature)?;
-    }
-
-    let fees = partial_vouchers
-        .iter()
-        .map(|pv| pv.voucher.fees)
-        .fold(U256::zero(), |sum, fees| sum.saturating_add(fees));
-    if fees == U256::zero() {
-        return Err(VoucherError::NoValue);
-    }
-
-    // Create signature for complete voucher
-    let mut message = Vec::new();
-    message.extend_from_slice(allocation_id);
-    message.extend_from_slice(&to_be_bytes(fees));
-    let signature = sign(&message, voucher_signer)?;
-
-    Ok(Voucher {
-        allocation_id: *allocation_id,
-        fees,
-        signature,
-    })
 }